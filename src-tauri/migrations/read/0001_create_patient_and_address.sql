@@ -0,0 +1,18 @@
+CREATE TABLE Patient (
+    id TEXT PRIMARY KEY,
+    stream_id TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    age INTEGER NOT NULL,
+    phone TEXT NOT NULL,
+    email TEXT NOT NULL
+);
+
+CREATE TABLE Address (
+    patient_id TEXT PRIMARY KEY,
+    street TEXT NOT NULL,
+    city TEXT NOT NULL,
+    state TEXT NOT NULL,
+    zip TEXT NOT NULL,
+    FOREIGN KEY (patient_id) REFERENCES Patient(id)
+);
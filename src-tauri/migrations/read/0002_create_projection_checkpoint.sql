@@ -0,0 +1,6 @@
+CREATE TABLE ProjectionCheckpoint (
+    projection TEXT NOT NULL,
+    stream_id TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    PRIMARY KEY (projection, stream_id)
+);
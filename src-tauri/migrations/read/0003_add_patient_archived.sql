@@ -0,0 +1 @@
+ALTER TABLE Patient ADD COLUMN archived INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1 @@
+ALTER TABLE Patient ADD COLUMN erased INTEGER NOT NULL DEFAULT 0;
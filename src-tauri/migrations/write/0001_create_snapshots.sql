@@ -0,0 +1,7 @@
+CREATE TABLE snapshots (
+    stream_id TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    state TEXT NOT NULL,
+    created_utc TEXT NOT NULL,
+    PRIMARY KEY (stream_id, version)
+);
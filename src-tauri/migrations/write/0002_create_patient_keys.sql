@@ -0,0 +1,5 @@
+CREATE TABLE patient_keys (
+    stream_id TEXT NOT NULL PRIMARY KEY,
+    key BLOB NOT NULL,
+    created_utc TEXT NOT NULL
+);
@@ -0,0 +1,3 @@
+-- Snapshots written before sealing hold PII in clear text. They are only a cache
+-- of the events, so drop them and let the next commands write sealed ones.
+DELETE FROM snapshots;
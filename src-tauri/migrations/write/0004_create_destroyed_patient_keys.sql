@@ -0,0 +1,6 @@
+-- One row per patient whose key was destroyed, so no later append can create a new key
+-- for a stream that is still sealed under the old one
+CREATE TABLE destroyed_patient_keys (
+    stream_id TEXT NOT NULL PRIMARY KEY,
+    destroyed_utc TEXT NOT NULL
+);
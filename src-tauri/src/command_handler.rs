@@ -0,0 +1,486 @@
+use crate::snapshot_store::SnapshotStore;
+use crate::types::errors::{CommandError, ConcurrencyConflict};
+use anyhow::Result;
+use cosmo_store::common::i64_event_version::EventVersion;
+use cosmo_store::traits::event_store::EventStore;
+use cosmo_store::types::event_read::EventRead;
+use cosmo_store::types::event_read_range::EventsReadRange;
+use cosmo_store::types::event_write::EventWrite;
+use cosmo_store::types::expected_version::ExpectedVersion;
+use cosmo_store_util::aggregate::Aggregate;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug)]
+pub struct HandlerResult<State, Event, Meta, Version> {
+    pub(crate) events: Vec<EventRead<Event, Meta, Version>>,
+    pub(crate) state: State,
+}
+
+// How an aggregate's event is written to and read back from the store. The stored
+// payload can differ from the event type so reading can dispatch on the event name.
+pub trait EventCodec<Payload, Meta>: Sized {
+    fn encode(self, metadata: &Meta) -> Result<EventWrite<Payload, Meta>>;
+    fn decode(name: &str, data: &Payload, metadata: Option<&Meta>) -> Result<Self>;
+}
+
+pub fn decode_event_read<Event, Payload, Meta, Version>(
+    event: EventRead<Payload, Meta, Version>,
+) -> Result<EventRead<Event, Meta, Version>>
+where
+    Event: EventCodec<Payload, Meta>,
+{
+    let data = Event::decode(&event.name, &event.data, event.metadata.as_ref())?;
+    Ok(EventRead {
+        id: event.id,
+        correlation_id: event.correlation_id,
+        causation_id: event.causation_id,
+        stream_id: event.stream_id,
+        version: event.version,
+        name: event.name,
+        data,
+        metadata: event.metadata,
+        created_utc: event.created_utc,
+    })
+}
+
+pub trait StreamVersion {
+    fn number(&self) -> i64;
+    fn from_number(number: i64) -> Self;
+}
+
+impl StreamVersion for EventVersion {
+    fn number(&self) -> i64 {
+        self.0
+    }
+
+    fn from_number(number: i64) -> Self {
+        EventVersion(number)
+    }
+}
+
+// Folds the stream, runs the command and appends the resulting events. Generic over
+// any aggregate and store so a second aggregate can reuse it as is. State is always
+// rebuilt from the start of the stream or from the latest snapshot; the expected
+// version is only used for the concurrency check.
+pub async fn make_handler<A, S, Snap, State, Command, Event, Payload, Meta, Version>(
+    aggregate: &A,
+    store: &S,
+    snapshots: &Snap,
+    command: &Command,
+    metadata: &Meta,
+    stream_id: &str,
+    expected_version: &ExpectedVersion<Version>,
+) -> Result<HandlerResult<State, Event, Meta, Version>>
+where
+    A: Aggregate<State, Command, Event> + Sync,
+    S: EventStore<Payload, Meta, Version> + Sync,
+    Snap: SnapshotStore<State, Version> + Sync,
+    State: Send + Sync,
+    Command: Sync,
+    Version: Eq + PartialEq + StreamVersion + Send + Sync,
+    Event: EventCodec<Payload, Meta> + Send + Sync,
+    Payload: Serialize + for<'de> Deserialize<'de> + Send + Sync,
+    Meta: Clone + Serialize + for<'de> Deserialize<'de> + Send + Sync,
+{
+    let snapshot = snapshots.load(stream_id).await?;
+    let (initial_state, snapshot_version, range) = match snapshot {
+        Some(snapshot) => (
+            snapshot.state,
+            Some(snapshot.version.number()),
+            EventsReadRange::FromVersion(Version::from_number(snapshot.version.number() + 1)),
+        ),
+        None => (aggregate.init(), None, EventsReadRange::AllEvents),
+    };
+    let events = store
+        .get_events(stream_id, &range)
+        .await?
+        .into_iter()
+        .map(decode_event_read)
+        .collect::<Result<Vec<EventRead<Event, Meta, Version>>>>()?;
+    let current_version = events
+        .last()
+        .map(|event| event.version.number())
+        .or(snapshot_version);
+    check_expected_version(stream_id, expected_version, current_version)?;
+    let state = events
+        .iter()
+        .fold(initial_state, |a, b| aggregate.apply(a, &b.data));
+    let new_events = aggregate.execute(&state, command)?;
+    let new_state = new_events.iter().fold(state, |a, b| aggregate.apply(a, b));
+    let event_writes = new_events
+        .into_iter()
+        .map(|x| x.encode(metadata))
+        .collect::<Result<Vec<EventWrite<Payload, Meta>>>>()?;
+    let written = match store
+        .append_events(stream_id, expected_version, event_writes)
+        .await
+    {
+        Ok(written) => written,
+        // Another writer can append between the check above and this append. The store
+        // rejects it then, and it is reported as the same typed conflict.
+        Err(error) => {
+            let current_version = store
+                .get_stream(stream_id)
+                .await
+                .ok()
+                .map(|stream| stream.last_version.number());
+            check_expected_version(stream_id, expected_version, current_version)?;
+            return Err(error);
+        }
+    };
+    let written = written
+        .into_iter()
+        .map(decode_event_read)
+        .collect::<Result<Vec<EventRead<Event, Meta, Version>>>>()?;
+    if let Some(last) = written.last() {
+        // The events are already appended, so a failed snapshot only costs a longer replay
+        if let Err(error) = snapshots
+            .save(
+                stream_id,
+                current_version.unwrap_or(0),
+                &last.version,
+                &new_state,
+            )
+            .await
+        {
+            log::warn!("Snapshot of {} failed: {:#}", stream_id, error);
+        }
+    }
+    Ok(HandlerResult {
+        events: written,
+        state: new_state,
+    })
+}
+
+fn check_expected_version<Version: StreamVersion>(
+    stream_id: &str,
+    expected_version: &ExpectedVersion<Version>,
+    current_version: Option<i64>,
+) -> Result<()> {
+    // Exact names the next version, so the stream is expected to end just before it
+    let expected = match expected_version {
+        ExpectedVersion::Any => return Ok(()),
+        ExpectedVersion::NoStream => 0,
+        ExpectedVersion::Exact(version) => version.number() - 1,
+    };
+    let current = current_version.unwrap_or(0);
+    if expected != current {
+        return Err(CommandError::ConcurrencyConflict(ConcurrencyConflict {
+            stream_id: stream_id.to_string(),
+            expected_version: expected,
+            current_version: current,
+        })
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::in_memory_store::InMemoryEventStore;
+    use crate::snapshot_store::{NoSnapshots, Snapshot};
+    use crate::test_support::{
+        add_patient, test_address, test_meta, update_address, update_patient,
+    };
+    use crate::types::address::Address;
+    use crate::types::aggregate::PATIENT_AGGREGATE;
+    use crate::types::commands::{patient_stream_id, PatientCommand, StreamId};
+    use crate::types::event_meta::PatientEventMeta;
+    use crate::types::events::{PatientEvent, PatientUpdated, PATIENT_ADDED, PATIENT_UPDATED};
+    use crate::types::patient::Patient;
+    use async_trait::async_trait;
+    use cosmo_store::types::stream::Stream;
+    use cosmo_store::types::stream_read_filter::StreamsReadFilter;
+    use serde_json::Value;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use uuid::Uuid;
+
+    type PatientResult =
+        HandlerResult<Option<Patient>, PatientEvent, PatientEventMeta, EventVersion>;
+
+    async fn handle<S>(store: &S, command: &PatientCommand) -> Result<PatientResult>
+    where
+        S: EventStore<Value, PatientEventMeta, EventVersion> + Sync,
+    {
+        handle_with(store, &NoSnapshots, command).await
+    }
+
+    async fn handle_with<S, Snap>(
+        store: &S,
+        snapshots: &Snap,
+        command: &PatientCommand,
+    ) -> Result<PatientResult>
+    where
+        S: EventStore<Value, PatientEventMeta, EventVersion> + Sync,
+        Snap: SnapshotStore<Option<Patient>, EventVersion> + Sync,
+    {
+        make_handler::<_, _, _, _, _, _, Value, _, _>(
+            &PATIENT_AGGREGATE,
+            store,
+            snapshots,
+            command,
+            &test_meta(),
+            &StreamId::from(command.clone()),
+            &ExpectedVersion::from(command.clone()),
+        )
+        .await
+    }
+
+    // Appends a competing event just before the next append, like a second window that
+    // passed the version check at the same moment
+    #[derive(Default)]
+    struct RacingStore {
+        inner: InMemoryEventStore,
+        race_next_append: AtomicBool,
+    }
+
+    #[async_trait]
+    impl EventStore<Value, PatientEventMeta, EventVersion> for RacingStore {
+        async fn append_event(
+            &self,
+            stream_id: &str,
+            version: &ExpectedVersion<EventVersion>,
+            payload: &EventWrite<Value, PatientEventMeta>,
+        ) -> Result<EventRead<Value, PatientEventMeta, EventVersion>> {
+            self.inner.append_event(stream_id, version, payload).await
+        }
+
+        async fn append_events(
+            &self,
+            stream_id: &str,
+            version: &ExpectedVersion<EventVersion>,
+            payload: Vec<EventWrite<Value, PatientEventMeta>>,
+        ) -> Result<Vec<EventRead<Value, PatientEventMeta, EventVersion>>> {
+            if self.race_next_append.swap(false, Ordering::SeqCst) {
+                let competing = PatientEvent::PatientUpdated(PatientUpdated {
+                    name: "Other Window".to_string(),
+                    age: 50,
+                    phone: "555-0199".to_string(),
+                    email: "other@example.com".to_string(),
+                })
+                .encode(&test_meta())?;
+                self.inner
+                    .append_events(stream_id, &ExpectedVersion::Any, vec![competing])
+                    .await?;
+            }
+            self.inner.append_events(stream_id, version, payload).await
+        }
+
+        async fn get_event(
+            &self,
+            stream_id: &str,
+            version: &EventVersion,
+        ) -> Result<EventRead<Value, PatientEventMeta, EventVersion>> {
+            self.inner.get_event(stream_id, version).await
+        }
+
+        async fn get_events(
+            &self,
+            stream_id: &str,
+            version: &EventsReadRange<EventVersion>,
+        ) -> Result<Vec<EventRead<Value, PatientEventMeta, EventVersion>>> {
+            self.inner.get_events(stream_id, version).await
+        }
+
+        async fn get_events_by_correlation_id(
+            &self,
+            correlation_id: &Uuid,
+        ) -> Result<Vec<EventRead<Value, PatientEventMeta, EventVersion>>> {
+            self.inner
+                .get_events_by_correlation_id(correlation_id)
+                .await
+        }
+
+        async fn get_streams(
+            &self,
+            filter: &StreamsReadFilter,
+        ) -> Result<Vec<Stream<EventVersion>>> {
+            EventStore::<Value, PatientEventMeta, EventVersion>::get_streams(&self.inner, filter)
+                .await
+        }
+
+        async fn get_stream(&self, stream_id: &str) -> Result<Stream<EventVersion>> {
+            EventStore::<Value, PatientEventMeta, EventVersion>::get_stream(&self.inner, stream_id)
+                .await
+        }
+    }
+
+    #[tokio::test]
+    async fn conflicting_append_is_a_concurrency_conflict() {
+        let id = Uuid::new_v4();
+        let store = RacingStore::default();
+        handle(&store, &add_patient(id)).await.unwrap();
+
+        store.race_next_append.store(true, Ordering::SeqCst);
+        let error = handle(&store, &update_patient(id, 1, "John Doe"))
+            .await
+            .unwrap_err();
+
+        match error.downcast_ref::<CommandError>() {
+            Some(CommandError::ConcurrencyConflict(conflict)) => {
+                assert_eq!(conflict.expected_version, 1);
+                assert_eq!(conflict.current_version, 2);
+            }
+            other => panic!("expected a concurrency conflict, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn returns_written_events_and_folded_state() {
+        let id = Uuid::new_v4();
+        let store = InMemoryEventStore::new();
+
+        let res = handle(&store, &add_patient(id)).await.unwrap();
+
+        assert_eq!(res.events.len(), 1);
+        let added = &res.events[0];
+        assert_eq!(added.version, EventVersion(1));
+        assert_eq!(added.name, PATIENT_ADDED);
+        match &added.data {
+            PatientEvent::PatientAdded(event) => assert_eq!(event.id, id),
+            other => panic!("expected PatientAdded, got {:?}", other),
+        }
+        let patient = res.state.unwrap();
+        assert_eq!(patient.id, id);
+        assert_eq!(patient.name, "Jane Doe");
+    }
+
+    #[tokio::test]
+    async fn folds_new_events_onto_the_stored_stream() {
+        let id = Uuid::new_v4();
+        let store = InMemoryEventStore::new();
+        handle(&store, &add_patient(id)).await.unwrap();
+
+        let res = handle(&store, &update_patient(id, 1, "John Doe"))
+            .await
+            .unwrap();
+
+        assert_eq!(res.events.len(), 1);
+        assert_eq!(res.events[0].version, EventVersion(2));
+        assert_eq!(res.events[0].name, PATIENT_UPDATED);
+        let patient = res.state.unwrap();
+        assert_eq!(patient.name, "John Doe");
+        assert_eq!(patient.age, 41);
+        // Untouched by the update, so it comes from replaying PatientAdded
+        assert_eq!(patient.address, test_address());
+    }
+
+    #[tokio::test]
+    async fn stale_version_is_a_concurrency_conflict() {
+        let id = Uuid::new_v4();
+        let store = InMemoryEventStore::new();
+        handle(&store, &add_patient(id)).await.unwrap();
+        handle(&store, &update_patient(id, 1, "John Doe"))
+            .await
+            .unwrap();
+
+        let error = handle(&store, &update_patient(id, 1, "Jim Doe"))
+            .await
+            .unwrap_err();
+
+        match error.downcast_ref::<CommandError>() {
+            Some(CommandError::ConcurrencyConflict(conflict)) => {
+                assert_eq!(conflict.expected_version, 1);
+                assert_eq!(conflict.current_version, 2);
+            }
+            other => panic!("expected a concurrency conflict, got {:?}", other),
+        }
+        let stream = EventStore::<Value, PatientEventMeta, EventVersion>::get_stream(
+            &store,
+            &patient_stream_id(&id),
+        )
+        .await
+        .unwrap();
+        assert_eq!(stream.last_version, EventVersion(2));
+    }
+
+    // Always hands out the same snapshot and never saves one
+    struct FixedSnapshot(Snapshot<Option<Patient>, EventVersion>);
+
+    #[async_trait]
+    impl SnapshotStore<Option<Patient>, EventVersion> for FixedSnapshot {
+        async fn load(
+            &self,
+            _stream_id: &str,
+        ) -> Result<Option<Snapshot<Option<Patient>, EventVersion>>> {
+            Ok(Some(self.0.clone()))
+        }
+
+        async fn save(
+            &self,
+            _stream_id: &str,
+            _previous_version: i64,
+            _version: &EventVersion,
+            _state: &Option<Patient>,
+        ) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn address(street: &str) -> Address {
+        Address {
+            street: street.to_string(),
+            ..test_address()
+        }
+    }
+
+    // Regression: the replay range used to start at the command's expected version,
+    // so every update past the first event failed with "Patient not found"
+    #[tokio::test]
+    async fn update_after_several_events_replays_the_whole_stream() {
+        let id = Uuid::new_v4();
+        let store = InMemoryEventStore::new();
+        handle(&store, &add_patient(id)).await.unwrap();
+        handle(&store, &update_patient(id, 1, "John Doe"))
+            .await
+            .unwrap();
+        handle(&store, &update_address(id, 2, address("2 Oak St")))
+            .await
+            .unwrap();
+
+        let res = handle(&store, &update_patient(id, 3, "Jim Doe"))
+            .await
+            .unwrap();
+
+        assert_eq!(res.events[0].version, EventVersion(4));
+        let patient = res.state.unwrap();
+        assert_eq!(patient.id, id);
+        assert_eq!(patient.name, "Jim Doe");
+        assert_eq!(patient.address, address("2 Oak St"));
+    }
+
+    #[tokio::test]
+    async fn update_after_a_snapshot_replays_only_newer_events() {
+        let id = Uuid::new_v4();
+        let store = InMemoryEventStore::new();
+        handle(&store, &add_patient(id)).await.unwrap();
+        let at_two = handle(&store, &update_patient(id, 1, "John Doe"))
+            .await
+            .unwrap();
+        handle(&store, &update_address(id, 2, address("2 Oak St")))
+            .await
+            .unwrap();
+        // Marked so the result shows whether state came from the snapshot or a full replay
+        let mut snapshot_state = at_two.state.unwrap();
+        snapshot_state.phone = "555-SNAP".to_string();
+        let snapshots = FixedSnapshot(Snapshot {
+            version: EventVersion(2),
+            state: Some(snapshot_state),
+        });
+
+        let res = handle_with(
+            &store,
+            &snapshots,
+            &update_address(id, 3, address("3 Pine St")),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(res.events[0].version, EventVersion(4));
+        let patient = res.state.unwrap();
+        assert_eq!(patient.name, "John Doe");
+        assert_eq!(patient.phone, "555-SNAP");
+        assert_eq!(patient.address, address("3 Pine St"));
+    }
+}
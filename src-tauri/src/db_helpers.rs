@@ -1,84 +1,277 @@
+use crate::types::errors::CommandError;
 use crate::types::patient::Patient;
+use crate::types::patient_db::PatientAddressDB;
+use crate::types::patient_query::{PatientList, PatientListItem, PatientListQuery};
 use anyhow::Result;
-use sqlx::migrate::MigrateDatabase;
-use sqlx::{Error, Pool, Sqlite};
+use sqlx::migrate::{MigrateDatabase, Migrator};
+use sqlx::sqlite::SqliteConnectOptions;
+use sqlx::{Pool, Sqlite, Transaction};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+// Set to 1/true to wipe write.db and read.db on startup. Dev/reset only.
+pub const RESET_DB_ENV: &str = "TAURI_ES_RESET_DB";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DbStartupMode {
+    Open,
+    Recreate,
+}
+
+impl DbStartupMode {
+    pub fn from_env() -> Self {
+        match std::env::var(RESET_DB_ENV) {
+            Ok(value) if value == "1" || value.eq_ignore_ascii_case("true") => {
+                DbStartupMode::Recreate
+            }
+            _ => DbStartupMode::Open,
+        }
+    }
+}
+
+// secure_delete makes SQLite overwrite whatever it deletes, so destroyed keys and
+// replaced PII are not left behind in free pages of either database
+pub fn connect_options(conn: &str) -> Result<SqliteConnectOptions> {
+    Ok(SqliteConnectOptions::from_str(conn)?.pragma("secure_delete", "ON"))
+}
+
+// Copies the WAL back into the database file and truncates it, so pages overwritten by
+// an erase do not survive in the log either
+pub async fn checkpoint_wal(pool: &Pool<Sqlite>) -> Result<()> {
+    sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)")
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+// Returns true when the database did not exist before and was created empty
+pub async fn prepare_database(conn: &str, mode: DbStartupMode) -> anyhow::Result<bool> {
+    match mode {
+        DbStartupMode::Recreate => {
+            log::info!("Recreating database {}", conn);
+            recreate_database(conn).await?;
+            Ok(true)
+        }
+        DbStartupMode::Open => create_database_if_missing(conn).await,
+    }
+}
+
+pub async fn create_database_if_missing(conn: &str) -> anyhow::Result<bool> {
+    if Sqlite::database_exists(conn).await? {
+        log::info!("Opening existing db {}", conn);
+        return Ok(false);
+    }
+    Sqlite::create_database(conn).await?;
+    log::info!("Create db success for {}", conn);
+    Ok(true)
+}
 
 pub async fn recreate_database(conn: &str) -> anyhow::Result<()> {
     if Sqlite::database_exists(conn).await? {
         Sqlite::drop_database(conn).await?;
         match Sqlite::create_database(conn).await {
-            Ok(_) => println!("Create db success for {}", conn),
+            Ok(_) => log::info!("Create db success for {}", conn),
             Err(error) => panic!("error: {}", error),
         }
     } else {
         match Sqlite::create_database(conn).await {
-            Ok(_) => println!("Create db success for {}", conn),
+            Ok(_) => log::info!("Create db success for {}", conn),
             Err(error) => panic!("error: {}", error),
         }
     }
     Ok(())
 }
 
+// Name of the Patient/Address projection in ProjectionCheckpoint
+pub const PATIENT_PROJECTION: &str = "patient_read_model";
+
+// Returns false when the stored row is already at `version` or newer; nothing is written then
 pub async fn upsert_patient(
     read_pool: Pool<Sqlite>,
     p: Patient,
     version: i64,
     stream_id: String,
-) -> std::result::Result<(), Error> {
+) -> Result<bool, CommandError> {
     //Insert or Update into Patient and Address table with transaction for read model
-    println!("upsert_version: {:#?}", version);
-    println!("upsert_patient: {:#?}", p);
     let mut tx = read_pool.begin().await?;
-    let patient = sqlx::query("INSERT INTO Patient (id, stream_id, version,name, age, phone, email) VALUES ($1, $2, $3, $4, $5, $6, $7) ON CONFLICT(id) DO UPDATE SET version = $3, name = $4, age = $5, phone = $6, email = $7")
+    let written = write_patient(&mut tx, p, version, stream_id).await?;
+    if written {
+        tx.commit().await?;
+    } else {
+        tx.rollback().await?;
+    }
+    Ok(written)
+}
+
+// Writes the Patient and Address rows and the checkpoint inside the caller's transaction
+pub async fn write_patient(
+    tx: &mut Transaction<'_, Sqlite>,
+    p: Patient,
+    version: i64,
+    stream_id: String,
+) -> Result<bool, CommandError> {
+    log::debug!("Projecting patient {} at version {}", p.id, version);
+    let patient = sqlx::query("INSERT INTO Patient (id, stream_id, version,name, age, phone, email, archived, erased) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) ON CONFLICT(id) DO UPDATE SET version = $3, name = $4, age = $5, phone = $6, email = $7, archived = $8, erased = $9 WHERE excluded.version > Patient.version")
         .bind(p.id)
-        .bind(stream_id)
+        .bind(&stream_id)
         .bind(version)
         .bind(p.name)
         .bind(p.age)
         .bind(p.phone)
         .bind(p.email)
-        .execute(&mut *tx)
+        .bind(p.archived)
+        .bind(p.erased)
+        .execute(&mut **tx)
         .await?;
+    if patient.rows_affected() == 0 {
+        log::info!(
+            "Patient {} already projected at version {} or newer",
+            p.id,
+            version
+        );
+        return Ok(false);
+    }
 
-    let address = sqlx::query("INSERT INTO Address (patient_id, street, city, state, zip) VALUES ($1, $2, $3, $4, $5) ON CONFLICT(patient_id) DO UPDATE SET street = $2, city = $3, state = $4, zip = $5")
+    sqlx::query("INSERT INTO Address (patient_id, street, city, state, zip) VALUES ($1, $2, $3, $4, $5) ON CONFLICT(patient_id) DO UPDATE SET street = $2, city = $3, state = $4, zip = $5")
         .bind(p.id)
         .bind(p.address.street)
         .bind(p.address.city)
         .bind(p.address.state)
         .bind(p.address.zip)
-        .execute(&mut *tx)
+        .execute(&mut **tx)
         .await?;
 
-    println!("patient: {:#?}", patient);
-    println!("address: {:#?}", address);
+    // Same transaction as the rows, so the checkpoint never runs ahead of or behind them
+    sqlx::query("INSERT INTO ProjectionCheckpoint (projection, stream_id, version) VALUES ($1, $2, $3) ON CONFLICT(projection, stream_id) DO UPDATE SET version = $3 WHERE excluded.version > ProjectionCheckpoint.version")
+        .bind(PATIENT_PROJECTION)
+        .bind(stream_id)
+        .bind(version)
+        .execute(&mut **tx)
+        .await?;
 
-    tx.commit().await
+    Ok(true)
 }
 
-pub async fn setup_read_db(read_pool: Pool<Sqlite>) -> Result<()> {
+// Read-side schema lives in versioned migrations under src-tauri/migrations/read and
+// is recorded in _sqlx_migrations
+pub static READ_MIGRATOR: Migrator = sqlx::migrate!("./migrations/read");
+
+// Write-side tables that are ours rather than the event store's (snapshots)
+pub static WRITE_MIGRATOR: Migrator = sqlx::migrate!("./migrations/write");
+
+pub async fn setup_write_db(write_pool: Pool<Sqlite>) -> Result<()> {
+    WRITE_MIGRATOR.run(&write_pool).await?;
+    Ok(())
+}
+
+// Returns true when the read schema changed and the read model has to be rebuilt from events
+pub async fn setup_read_db(read_pool: Pool<Sqlite>) -> Result<bool> {
+    let applied_before = applied_migration_count(&read_pool).await?;
+    match READ_MIGRATOR.run(&read_pool).await {
+        Ok(()) => {
+            let applied_after = applied_migration_count(&read_pool).await?;
+            Ok(applied_after != applied_before)
+        }
+        Err(error) => {
+            // The read model is derived data, so start from an empty schema instead of panicking
+            log::warn!("Read migration failed: {}. Resetting read schema", error);
+            reset_read_schema(&read_pool).await?;
+            READ_MIGRATOR.run(&read_pool).await?;
+            Ok(true)
+        }
+    }
+}
+
+async fn applied_migration_count(read_pool: &Pool<Sqlite>) -> Result<i64> {
+    let has_table: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations'",
+    )
+    .fetch_one(read_pool)
+    .await?;
+    if has_table == 0 {
+        return Ok(0);
+    }
+    let applied: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM _sqlx_migrations WHERE success = 1")
+            .fetch_one(read_pool)
+            .await?;
+    Ok(applied)
+}
+
+async fn reset_read_schema(read_pool: &Pool<Sqlite>) -> Result<()> {
     sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS Patient (
-                id TEXT PRIMARY KEY,
-                stream_id TEXT NOT NULL,
-                version INTEGER NOT NULL,
-                name TEXT NOT NULL,
-                age INTEGER NOT NULL,
-                phone TEXT NOT NULL,
-                email TEXT NOT NULL
-            );
-
-            CREATE TABLE IF NOT EXISTS Address (
-                patient_id TEXT PRIMARY KEY,
-                street TEXT NOT NULL,
-                city TEXT NOT NULL,
-                state TEXT NOT NULL,
-                zip TEXT NOT NULL,
-                FOREIGN KEY (patient_id) REFERENCES Patient(id)
-            );
-        "#,
+        "DROP TABLE IF EXISTS ProjectionCheckpoint; DROP TABLE IF EXISTS Address; DROP TABLE IF EXISTS Patient; DROP TABLE IF EXISTS _sqlx_migrations;",
     )
-    .execute(&read_pool)
+    .execute(read_pool)
     .await?;
     Ok(())
 }
+
+// Only inside a transaction, so the rows are never seen empty by readers
+pub async fn clear_read_model(tx: &mut Transaction<'_, Sqlite>) -> Result<(), CommandError> {
+    sqlx::query("DELETE FROM Address")
+        .execute(&mut **tx)
+        .await?;
+    sqlx::query("DELETE FROM Patient")
+        .execute(&mut **tx)
+        .await?;
+    sqlx::query("DELETE FROM ProjectionCheckpoint WHERE projection = $1")
+        .bind(PATIENT_PROJECTION)
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
+}
+
+// Last projected version per stream for one projection
+pub async fn get_projection_checkpoints(
+    read_pool: Pool<Sqlite>,
+    projection: &str,
+) -> Result<HashMap<String, i64>, CommandError> {
+    let rows: Vec<(String, i64)> =
+        sqlx::query_as("SELECT stream_id, version FROM ProjectionCheckpoint WHERE projection = $1")
+            .bind(projection)
+            .fetch_all(&read_pool)
+            .await?;
+    Ok(rows.into_iter().collect())
+}
+
+pub async fn get_erased_stream_ids(read_pool: Pool<Sqlite>) -> Result<Vec<String>, CommandError> {
+    let stream_ids = sqlx::query_scalar("SELECT stream_id FROM Patient WHERE erased = 1")
+        .fetch_all(&read_pool)
+        .await?;
+    Ok(stream_ids)
+}
+
+pub async fn get_patient_list(
+    read_pool: Pool<Sqlite>,
+    query: PatientListQuery,
+) -> Result<PatientList, CommandError> {
+    let sort_by = query.sort_by.unwrap_or_default();
+    let sort_direction = query.sort_direction.unwrap_or_default();
+    let include_archived = query.include_archived.unwrap_or(false);
+    let sql = format!(
+        "SELECT p.id, p.stream_id, p.version, p.name, p.age, p.phone, p.email, p.archived, p.erased, a.street, a.city, a.state, a.zip FROM Patient p INNER JOIN Address a ON a.patient_id = p.id WHERE ($1 OR p.archived = 0) ORDER BY {} {}, p.id LIMIT $2 OFFSET $3",
+        sort_by.column(),
+        sort_direction.keyword()
+    );
+    let rows = sqlx::query_as::<_, PatientAddressDB>(&sql)
+        .bind(include_archived)
+        .bind(query.page_size())
+        .bind(query.offset())
+        .fetch_all(&read_pool)
+        .await?;
+
+    let total: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM Patient p INNER JOIN Address a ON a.patient_id = p.id WHERE ($1 OR p.archived = 0)",
+    )
+    .bind(include_archived)
+    .fetch_one(&read_pool)
+    .await?;
+
+    Ok(PatientList {
+        items: rows.into_iter().map(PatientListItem::from).collect(),
+        total,
+        page: query.page(),
+        page_size: query.page_size(),
+    })
+}
@@ -0,0 +1,260 @@
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use cosmo_store::common::i64_event_version::EventVersion;
+use cosmo_store::traits::event_store::EventStore;
+use cosmo_store::types::event_read::EventRead;
+use cosmo_store::types::event_read_range::EventsReadRange;
+use cosmo_store::types::event_write::EventWrite;
+use cosmo_store::types::expected_version::ExpectedVersion;
+use cosmo_store::types::stream::Stream;
+use cosmo_store::types::stream_read_filter::StreamsReadFilter;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+// Events are kept as JSON, like EventStoreSQLXSqlite does, so payloads go through the
+// same serde round trip as they would against write.db
+#[derive(Clone, Debug)]
+struct StoredEvent {
+    id: Uuid,
+    correlation_id: Option<Uuid>,
+    causation_id: Option<Uuid>,
+    stream_id: String,
+    version: i64,
+    name: String,
+    data: Value,
+    metadata: Option<Value>,
+    created_utc: DateTime<Utc>,
+}
+
+impl StoredEvent {
+    fn read<Payload, Meta>(&self) -> Result<EventRead<Payload, Meta, EventVersion>>
+    where
+        Payload: for<'de> Deserialize<'de>,
+        Meta: for<'de> Deserialize<'de>,
+    {
+        Ok(EventRead {
+            id: self.id,
+            correlation_id: self.correlation_id,
+            causation_id: self.causation_id,
+            stream_id: self.stream_id.clone(),
+            version: EventVersion(self.version),
+            name: self.name.clone(),
+            data: serde_json::from_value(self.data.clone())?,
+            metadata: match &self.metadata {
+                Some(metadata) => Some(serde_json::from_value(metadata.clone())?),
+                None => None,
+            },
+            created_utc: self.created_utc,
+        })
+    }
+}
+
+// EventStore kept entirely in memory for tests. Same ExpectedVersion and EventsReadRange
+// semantics as the SQLite store, without needing a database file.
+#[derive(Clone, Debug, Default)]
+pub struct InMemoryEventStore {
+    streams: Arc<Mutex<BTreeMap<String, Vec<StoredEvent>>>>,
+}
+
+impl InMemoryEventStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+fn check_version(
+    stream_id: &str,
+    expected_version: &ExpectedVersion<EventVersion>,
+    current_version: Option<i64>,
+) -> Result<()> {
+    match (expected_version, current_version) {
+        (ExpectedVersion::Any, _) => Ok(()),
+        (ExpectedVersion::NoStream, None) => Ok(()),
+        (ExpectedVersion::NoStream, Some(current)) => {
+            bail!("Stream {} already exists at version {}", stream_id, current)
+        }
+        // Like CosmoStore, Exact is the version the next event is expected to get
+        (ExpectedVersion::Exact(expected), current) if expected.0 == current.unwrap_or(0) + 1 => {
+            Ok(())
+        }
+        (ExpectedVersion::Exact(expected), current) => bail!(
+            "Stream {} expected next version {} but is at {}",
+            stream_id,
+            expected.0,
+            current.unwrap_or(0)
+        ),
+    }
+}
+
+fn in_range(version: i64, range: &EventsReadRange<EventVersion>) -> bool {
+    match range {
+        EventsReadRange::AllEvents => true,
+        EventsReadRange::FromVersion(from) => version >= from.0,
+        EventsReadRange::ToVersion(to) => version <= to.0,
+        EventsReadRange::VersionRange {
+            from_version,
+            to_version,
+        } => version >= from_version.0 && version <= to_version.0,
+    }
+}
+
+fn matches_filter(stream_id: &str, filter: &StreamsReadFilter) -> bool {
+    match filter {
+        StreamsReadFilter::AllStreams => true,
+        StreamsReadFilter::StartsWith(prefix) => stream_id.starts_with(prefix.as_str()),
+        StreamsReadFilter::EndsWith(suffix) => stream_id.ends_with(suffix.as_str()),
+        StreamsReadFilter::Contains(part) => stream_id.contains(part.as_str()),
+    }
+}
+
+fn to_stream(stream_id: &str, events: &[StoredEvent]) -> Option<Stream<EventVersion>> {
+    events.last().map(|last| Stream {
+        id: stream_id.to_string(),
+        last_version: EventVersion(last.version),
+        last_updated_utc: last.created_utc,
+    })
+}
+
+#[async_trait]
+impl<Payload, Meta> EventStore<Payload, Meta, EventVersion> for InMemoryEventStore
+where
+    Payload: Clone + Serialize + for<'de> Deserialize<'de> + Send + Sync + 'static,
+    Meta: Clone + Serialize + for<'de> Deserialize<'de> + Send + Sync + 'static,
+{
+    async fn append_event(
+        &self,
+        stream_id: &str,
+        version: &ExpectedVersion<EventVersion>,
+        payload: &EventWrite<Payload, Meta>,
+    ) -> Result<EventRead<Payload, Meta, EventVersion>> {
+        let event = EventWrite {
+            id: payload.id,
+            correlation_id: payload.correlation_id,
+            causation_id: payload.causation_id,
+            name: payload.name.clone(),
+            data: payload.data.clone(),
+            metadata: payload.metadata.clone(),
+        };
+        let mut events = self.append_events(stream_id, version, vec![event]).await?;
+        events
+            .pop()
+            .ok_or_else(|| anyhow!("No event appended to {}", stream_id))
+    }
+
+    async fn append_events(
+        &self,
+        stream_id: &str,
+        version: &ExpectedVersion<EventVersion>,
+        payload: Vec<EventWrite<Payload, Meta>>,
+    ) -> Result<Vec<EventRead<Payload, Meta, EventVersion>>> {
+        let mut streams = self
+            .streams
+            .lock()
+            .map_err(|_| anyhow!("In-memory store lock poisoned"))?;
+        let stream = streams.entry(stream_id.to_string()).or_default();
+        let current_version = stream.last().map(|event| event.version);
+        check_version(stream_id, version, current_version)?;
+
+        let created_utc = Utc::now();
+        let mut next_version = current_version.unwrap_or(0);
+        let mut appended = Vec::with_capacity(payload.len());
+        for event in payload {
+            next_version += 1;
+            appended.push(StoredEvent {
+                id: event.id,
+                correlation_id: event.correlation_id,
+                causation_id: event.causation_id,
+                stream_id: stream_id.to_string(),
+                version: next_version,
+                name: event.name,
+                data: serde_json::to_value(event.data)?,
+                metadata: match event.metadata {
+                    Some(metadata) => Some(serde_json::to_value(metadata)?),
+                    None => None,
+                },
+                created_utc,
+            });
+        }
+        stream.extend(appended.iter().cloned());
+        appended.iter().map(|event| event.read()).collect()
+    }
+
+    async fn get_event(
+        &self,
+        stream_id: &str,
+        version: &EventVersion,
+    ) -> Result<EventRead<Payload, Meta, EventVersion>> {
+        let streams = self
+            .streams
+            .lock()
+            .map_err(|_| anyhow!("In-memory store lock poisoned"))?;
+        streams
+            .get(stream_id)
+            .and_then(|events| events.iter().find(|event| event.version == version.0))
+            .ok_or_else(|| anyhow!("Event {} not found in {}", version.0, stream_id))?
+            .read()
+    }
+
+    async fn get_events(
+        &self,
+        stream_id: &str,
+        version: &EventsReadRange<EventVersion>,
+    ) -> Result<Vec<EventRead<Payload, Meta, EventVersion>>> {
+        let streams = self
+            .streams
+            .lock()
+            .map_err(|_| anyhow!("In-memory store lock poisoned"))?;
+        match streams.get(stream_id) {
+            Some(events) => events
+                .iter()
+                .filter(|event| in_range(event.version, version))
+                .map(|event| event.read())
+                .collect(),
+            None => Ok(vec![]),
+        }
+    }
+
+    async fn get_events_by_correlation_id(
+        &self,
+        correlation_id: &Uuid,
+    ) -> Result<Vec<EventRead<Payload, Meta, EventVersion>>> {
+        let streams = self
+            .streams
+            .lock()
+            .map_err(|_| anyhow!("In-memory store lock poisoned"))?;
+        let mut events: Vec<&StoredEvent> = streams
+            .values()
+            .flatten()
+            .filter(|event| event.correlation_id == Some(*correlation_id))
+            .collect();
+        events.sort_by_key(|event| event.created_utc);
+        events.into_iter().map(|event| event.read()).collect()
+    }
+
+    async fn get_streams(&self, filter: &StreamsReadFilter) -> Result<Vec<Stream<EventVersion>>> {
+        let streams = self
+            .streams
+            .lock()
+            .map_err(|_| anyhow!("In-memory store lock poisoned"))?;
+        Ok(streams
+            .iter()
+            .filter(|(stream_id, _)| matches_filter(stream_id, filter))
+            .filter_map(|(stream_id, events)| to_stream(stream_id, events))
+            .collect())
+    }
+
+    async fn get_stream(&self, stream_id: &str) -> Result<Stream<EventVersion>> {
+        let streams = self
+            .streams
+            .lock()
+            .map_err(|_| anyhow!("In-memory store lock poisoned"))?;
+        streams
+            .get(stream_id)
+            .and_then(|events| to_stream(stream_id, events))
+            .ok_or_else(|| anyhow!("Stream {} not found", stream_id))
+    }
+}
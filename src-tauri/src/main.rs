@@ -1,38 +1,55 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+mod command_handler;
 mod db_helpers;
+#[cfg(test)]
+mod in_memory_store;
 mod patient_helper;
+mod pii_store;
+mod projector;
+mod snapshot_store;
+#[cfg(test)]
+mod test_support;
 mod types;
-use std::sync::{Arc, Mutex};
+use std::path::Path;
 
-use crate::patient_helper::{
-    get_patient_meta, make_handler, process_patient_command, process_patient_events,
-};
-use crate::types::address::Address;
+use crate::patient_helper::process_and_project_patient_command;
 use crate::types::commands::{
-    AddPatient, PatientCommand, StreamId, UpdatePatient, UpdatePatientAddress,
+    patient_stream_id, AddPatient, ArchivePatient, ErasePatient, PatientCommand, RestorePatient,
+    UpdatePatient, UpdatePatientAddress,
+};
+use crate::types::errors::CommandError;
+use crate::types::event_meta::{CommandContext, PatientEventMeta};
+use crate::types::notifications::PATIENT_READ_MODEL_REBUILT_NOTIFICATION;
+use crate::types::patient_db::PatientMeta;
+use crate::types::patient_history::{AsOf, PatientAsOf, PatientHistoryEntry};
+use crate::types::patient_input::{
+    AddPatientInput, PatientCreated, PatientVersionInput, UpdatePatientAddressInput,
+    UpdatePatientInput,
 };
+use crate::types::patient_query::{PatientList, PatientListQuery};
 use anyhow::Result;
-use cosmo_store::common::i64_event_version::EventVersion;
-use cosmo_store::traits::event_store::EventStore;
-use cosmo_store::types::event_read::EventRead;
-use cosmo_store::types::event_read_range::EventsReadRange;
-use cosmo_store::types::expected_version::ExpectedVersion;
 use cosmo_store_sqlx_sqlite::event_store_sqlx_sqlite::EventStoreSQLXSqlite;
-use cosmo_store_util::aggregate::Aggregate;
 use sqlx::sqlite::SqlitePoolOptions;
-use tauri::State;
-use types::aggregate::PATIENT_AGGREGATE;
-use types::events::PatientEvent;
+use tauri::{AppHandle, Emitter, Manager, State};
 use uuid::Uuid;
 
-use crate::db_helpers::{recreate_database, setup_read_db};
+use crate::db_helpers::{
+    connect_options, get_patient_list, prepare_database, setup_read_db, setup_write_db,
+    DbStartupMode,
+};
+use crate::pii_store::{PatientKeyStore, PiiEventStore};
+use crate::snapshot_store::SqliteSnapshotStore;
+
+// Save the folded patient state every this many events
+const PATIENT_SNAPSHOT_EVERY: i64 = 20;
 
 struct AppState {
     read_db_pool: sqlx::SqlitePool,
-    write_db_pool: sqlx::SqlitePool,
-    store: EventStoreSQLXSqlite,
+    store: PiiEventStore,
+    snapshots: SqliteSnapshotStore,
+    device_id: Uuid,
 }
 
 // Learn more about Tauri commands at https://tauri.app/v1/guides/features/command
@@ -42,97 +59,287 @@ fn greet(name: &str) -> String {
 }
 
 #[tauri::command]
-fn get_patients() -> String {
-    format!("patient list")
+async fn get_patients<'a>(
+    state: State<'a, AppState>,
+    query: Option<PatientListQuery>,
+) -> Result<PatientList, CommandError> {
+    let patients = get_patient_list(state.read_db_pool.clone(), query.unwrap_or_default()).await?;
+    Ok(patients)
 }
 
-#[tauri::command]
-async fn add_patient<'a>(state: State<'a, AppState>) -> Result<String, tauri::Error> {
-    let store = &state.store;
-    let store = store.clone();
+// Runs a patient command through the store and the read model and returns the
+// stream's version after it
+async fn execute_patient_command(
+    app: &AppHandle,
+    state: &AppState,
+    patient_command: PatientCommand,
+    context: Option<CommandContext>,
+) -> Result<i64, CommandError> {
+    let metadata = PatientEventMeta::new(context, state.device_id);
+    let patient_meta = PatientMeta::from(patient_command.clone());
+    let res = match patient_command {
+        PatientCommand::ErasePatient(_) => {
+            patient_helper::erase_patient(
+                app,
+                state.store.clone(),
+                state.snapshots.clone(),
+                state.read_db_pool.clone(),
+                &patient_command,
+                &metadata,
+            )
+            .await?
+        }
+        _ => {
+            process_and_project_patient_command(
+                app,
+                state.store.clone(),
+                state.snapshots.clone(),
+                state.read_db_pool.clone(),
+                &patient_command,
+                &metadata,
+            )
+            .await?
+        }
+    };
+    Ok(res
+        .events
+        .last()
+        .map_or(patient_meta.version, |event| event.version.0))
+}
 
+#[tauri::command]
+async fn add_patient<'a>(
+    app: AppHandle,
+    state: State<'a, AppState>,
+    input: AddPatientInput,
+    context: Option<CommandContext>,
+) -> Result<PatientCreated, CommandError> {
+    input.validate()?;
     let new_patient_id = Uuid::new_v4();
-    let new_patient_stream_id = format!("patient-{}", Uuid::new_v4().to_string());
+    let new_patient_stream_id = patient_stream_id(&new_patient_id);
     let patient_command = PatientCommand::AddPatient(AddPatient {
-        id: new_patient_id.clone(),
+        id: new_patient_id,
         stream_id: new_patient_stream_id.clone(),
-        name: "John Doe".to_string(),
-        address: Address {
-            street: "123 Main St".to_string(),
-            city: "Anytown".to_string(),
-            state: "NY".to_string(),
-            zip: "12345".to_string(),
-        },
+        name: input.name,
+        address: input.address,
         version: 0,
-        age: 42,
-        phone: "555-123-4567".to_string(),
-        email: "".to_string(),
+        age: input.age,
+        phone: input.phone,
+        email: input.email,
     });
-    let stream_id = StreamId::from(patient_command.clone());
-
-    let events_read_range = EventsReadRange::from(patient_command.clone());
-    let aggregate = PATIENT_AGGREGATE.clone();
-    let res = make_handler(
-        &aggregate,
-        &store,
-        &patient_command,
-        &stream_id,
-        &events_read_range,
-        &ExpectedVersion::Any,
-    )
-    .await?;
-
-    // Simple code works as below. But generic is not working
-    // let events: Vec<EventRead<PatientEvent, PatientEvent, EventVersion>> = store.get_events(&stream_id, &events_read_range).await?;
-    // let state = events
-    //     .iter()
-    //     .fold(aggregate.init(), |a, b| aggregate.apply(a, &b.data));
-    // let new_events = aggregate
-    //     .execute(&state, &patient_command)?
-    //     .iter()
-    //     .map(|x| x.clone().into())
-    //     .collect();
-    // let res = store
-    //     .append_events(&stream_id, &ExpectedVersion::Any, new_events)
-    //     .await?;
-
-    println!("res {:#?}", res);
-
-    Ok(format!("patient added"))
-}
-
-#[tokio::main]
-async fn main() -> Result<()> {
-    env_logger::init();
-    println!("Hello, world!");
 
-    let write_db_conn = format!("sqlite://{}", "write.db");
-    let read_db_conn = format!("sqlite://{}", "read.db");
+    let version = execute_patient_command(&app, &state, patient_command, context).await?;
+    Ok(PatientCreated {
+        id: new_patient_id,
+        stream_id: new_patient_stream_id,
+        version,
+    })
+}
+
+#[tauri::command]
+async fn update_patient<'a>(
+    app: AppHandle,
+    state: State<'a, AppState>,
+    input: UpdatePatientInput,
+    context: Option<CommandContext>,
+) -> Result<i64, CommandError> {
+    input.validate()?;
+    let patient_command = PatientCommand::UpdatePatient(UpdatePatient {
+        id: input.id,
+        stream_id: input.stream_id,
+        version: input.version,
+        name: input.name,
+        age: input.age,
+        phone: input.phone,
+        email: input.email,
+    });
+    execute_patient_command(&app, &state, patient_command, context).await
+}
+
+#[tauri::command]
+async fn update_patient_address<'a>(
+    app: AppHandle,
+    state: State<'a, AppState>,
+    input: UpdatePatientAddressInput,
+    context: Option<CommandContext>,
+) -> Result<i64, CommandError> {
+    input.validate()?;
+    let patient_command = PatientCommand::UpdatePatientAddress(UpdatePatientAddress {
+        id: input.id,
+        stream_id: input.stream_id,
+        version: input.version,
+        address: input.address,
+    });
+    execute_patient_command(&app, &state, patient_command, context).await
+}
+
+#[tauri::command]
+async fn archive_patient<'a>(
+    app: AppHandle,
+    state: State<'a, AppState>,
+    input: PatientVersionInput,
+    context: Option<CommandContext>,
+) -> Result<i64, CommandError> {
+    input.validate()?;
+    let patient_command = PatientCommand::ArchivePatient(ArchivePatient {
+        id: input.id,
+        stream_id: input.stream_id,
+        version: input.version,
+    });
+    execute_patient_command(&app, &state, patient_command, context).await
+}
 
-    recreate_database(&write_db_conn).await?;
-    recreate_database(&read_db_conn).await?;
+#[tauri::command]
+async fn restore_patient<'a>(
+    app: AppHandle,
+    state: State<'a, AppState>,
+    input: PatientVersionInput,
+    context: Option<CommandContext>,
+) -> Result<i64, CommandError> {
+    input.validate()?;
+    let patient_command = PatientCommand::RestorePatient(RestorePatient {
+        id: input.id,
+        stream_id: input.stream_id,
+        version: input.version,
+    });
+    execute_patient_command(&app, &state, patient_command, context).await
+}
+
+#[tauri::command]
+async fn erase_patient<'a>(
+    app: AppHandle,
+    state: State<'a, AppState>,
+    input: PatientVersionInput,
+    context: Option<CommandContext>,
+) -> Result<i64, CommandError> {
+    input.validate()?;
+    let patient_command = PatientCommand::ErasePatient(ErasePatient {
+        id: input.id,
+        stream_id: input.stream_id,
+        version: input.version,
+    });
+    execute_patient_command(&app, &state, patient_command, context).await
+}
+
+#[tauri::command]
+async fn get_patient_history<'a>(
+    state: State<'a, AppState>,
+    patient_id: Uuid,
+) -> Result<Vec<PatientHistoryEntry>, CommandError> {
+    let history = patient_helper::get_patient_history(state.store.clone(), patient_id).await?;
+    Ok(history)
+}
+
+#[tauri::command]
+async fn get_patient_as_of<'a>(
+    state: State<'a, AppState>,
+    patient_id: Uuid,
+    as_of: AsOf,
+) -> Result<PatientAsOf, CommandError> {
+    let patient = patient_helper::get_patient_as_of(state.store.clone(), patient_id, as_of).await?;
+    Ok(patient)
+}
+
+#[tauri::command]
+async fn rebuild_read_model<'a>(
+    app: AppHandle,
+    state: State<'a, AppState>,
+) -> Result<usize, CommandError> {
+    let rebuilt =
+        patient_helper::rebuild_read_model(state.store.clone(), state.read_db_pool.clone()).await?;
+    // Any row may have changed, so open windows reload the whole list
+    app.emit(PATIENT_READ_MODEL_REBUILT_NOTIFICATION, rebuilt)?;
+    Ok(rebuilt)
+}
+
+// Identifies this installation in event metadata; created once next to the databases
+fn installation_id(data_dir: &Path) -> Result<Uuid> {
+    let path = data_dir.join("installation_id");
+    if let Ok(existing) = std::fs::read_to_string(&path) {
+        if let Ok(id) = Uuid::parse_str(existing.trim()) {
+            return Ok(id);
+        }
+    }
+    let id = Uuid::new_v4();
+    std::fs::write(&path, id.to_string())?;
+    Ok(id)
+}
+
+async fn setup_app_state(data_dir: &Path, mode: DbStartupMode) -> Result<AppState> {
+    std::fs::create_dir_all(data_dir)?;
+    log::info!("Using data directory {}", data_dir.display());
+
+    let write_db_conn = format!("sqlite://{}", data_dir.join("write.db").display());
+    let read_db_conn = format!("sqlite://{}", data_dir.join("read.db").display());
+
+    prepare_database(&write_db_conn, mode).await?;
+    let read_db_created = prepare_database(&read_db_conn, mode).await?;
 
     let write_pool = SqlitePoolOptions::new()
         .max_connections(5)
-        .connect(&write_db_conn)
+        .connect_with(connect_options(&write_db_conn)?)
         .await?;
     let read_pool = SqlitePoolOptions::new()
         .max_connections(5)
-        .connect(&read_db_conn)
+        .connect_with(connect_options(&read_db_conn)?)
         .await?;
 
-    // Create Patient and Address Read Table
-    setup_read_db(read_pool.clone()).await?;
-    let store = EventStoreSQLXSqlite::new(&write_pool, "tauri_store").await?;
+    // Apply read migrations (Patient and Address tables)
+    let read_schema_changed = setup_read_db(read_pool.clone()).await?;
+    let event_store = EventStoreSQLXSqlite::new(&write_pool, "tauri_store").await?;
+    setup_write_db(write_pool.clone()).await?;
+    let keys = PatientKeyStore::new(write_pool.clone());
+    let store = PiiEventStore::new(event_store, keys.clone());
+    let snapshots = SqliteSnapshotStore::new(write_pool.clone(), PATIENT_SNAPSHOT_EVERY, keys);
+
+    if read_db_created || read_schema_changed {
+        patient_helper::rebuild_read_model(store.clone(), read_pool.clone()).await?;
+    }
+    patient_helper::finish_interrupted_erasures(&store, &snapshots, read_pool.clone()).await?;
+
+    Ok(AppState {
+        read_db_pool: read_pool,
+        store,
+        snapshots,
+        device_id: installation_id(data_dir)?,
+    })
+}
+
+fn main() -> Result<()> {
+    env_logger::init();
+    log::info!("Hello, world!");
 
     tauri::Builder::default()
         .plugin(tauri_plugin_shell::init())
-        .manage(AppState {
-            read_db_pool: read_pool,
-            write_db_pool: write_pool,
-            store: store.clone(),
+        .setup(|app| {
+            // Resolved from the `identifier` in tauri.conf.json
+            let data_dir = app.path().app_data_dir()?;
+            let app_state = tauri::async_runtime::block_on(setup_app_state(
+                &data_dir,
+                DbStartupMode::from_env(),
+            ))?;
+            tauri::async_runtime::spawn(projector::run_patient_projector(
+                app.handle().clone(),
+                app_state.store.clone(),
+                app_state.snapshots.clone(),
+                app_state.read_db_pool.clone(),
+            ));
+            app.manage(app_state);
+            Ok(())
         })
-        .invoke_handler(tauri::generate_handler![greet, add_patient, get_patients])
+        .invoke_handler(tauri::generate_handler![
+            greet,
+            add_patient,
+            update_patient,
+            update_patient_address,
+            archive_patient,
+            restore_patient,
+            erase_patient,
+            get_patients,
+            get_patient_history,
+            get_patient_as_of,
+            rebuild_read_model
+        ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 
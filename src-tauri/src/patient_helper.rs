@@ -1,77 +1,186 @@
-use crate::db_helpers::upsert_patient;
+use crate::command_handler::{decode_event_read, make_handler, HandlerResult};
+use crate::db_helpers::{
+    checkpoint_wal, clear_read_model, get_erased_stream_ids, upsert_patient, write_patient,
+};
+use crate::pii_store::PiiEventStore;
+use crate::snapshot_store::{SnapshotStore, SqliteSnapshotStore};
 use crate::types::address::Address;
 use crate::types::aggregate::PATIENT_AGGREGATE;
-use crate::types::commands::{PatientCommand, StreamId};
+use crate::types::commands::{patient_stream_id, PatientCommand, StreamId, PATIENT_STREAM_PREFIX};
+use crate::types::errors::CommandError;
+use crate::types::event_meta::PatientEventMeta;
 use crate::types::events::PatientEvent;
+use crate::types::notifications::{patient_notification_name, PatientNotification};
 use crate::types::patient::Patient;
 use crate::types::patient_db::{AddressDB, PatientDB, PatientMeta};
-use anyhow::{bail, Result};
+use crate::types::patient_history::{diff_patients, AsOf, PatientAsOf, PatientHistoryEntry};
+use anyhow::Result;
 use cosmo_store::common::i64_event_version::EventVersion;
 use cosmo_store::traits::event_store::EventStore;
 use cosmo_store::types::event_read::EventRead;
 use cosmo_store::types::event_read_range::EventsReadRange;
-use cosmo_store::types::event_write::EventWrite;
 use cosmo_store::types::expected_version::ExpectedVersion;
-use cosmo_store_sqlx_sqlite::event_store_sqlx_sqlite::EventStoreSQLXSqlite;
+use cosmo_store::types::stream_read_filter::StreamsReadFilter;
 use cosmo_store_util::aggregate::Aggregate;
-use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use sqlx::{Pool, Sqlite};
+use tauri::{AppHandle, Emitter};
 use uuid::Uuid;
 
+pub type PatientEventRead = EventRead<PatientEvent, PatientEventMeta, EventVersion>;
 
-//TODO: Copy of original Make Handler function that I m trying to make it work
-pub async fn make_handler<State, Command, Event, Meta, Version>(
-    aggregate: &dyn Aggregate<State, Command, Event>,
-    store: &dyn EventStore<Event, Meta, Version>,
-    command: &Command,
+// Reads a patient stream and decodes every event by its name
+pub async fn read_patient_events<S>(
+    store: &S,
     stream_id: &str,
-    range: &EventsReadRange<Version>,
-    expected_version: &ExpectedVersion<Version>,
-) -> Result<Vec<EventRead<Event, Meta, Version>>>
+    range: &EventsReadRange<EventVersion>,
+) -> Result<Vec<PatientEventRead>>
 where
-    Version: Eq + PartialEq,
-    Event: Into<EventWrite<Event, Meta>> + Clone + Serialize + for<'de> Deserialize<'de>,
-    Meta: Clone + Serialize + for<'de> Deserialize<'de>,
+    S: EventStore<Value, PatientEventMeta, EventVersion> + Sync,
 {
-    let events = store.get_events(stream_id, range).await?;
-    let state = events
-        .iter()
-        .fold(aggregate.init(), |a, b| aggregate.apply(a, &b.data));
-    let new_events = aggregate
-        .execute(&state, command)?
-        .iter()
-        .map(|x| x.clone().into())
-        .collect();
-    store
-        .append_events(stream_id, expected_version, new_events)
-        .await
+    let events: Vec<EventRead<Value, PatientEventMeta, EventVersion>> =
+        store.get_events(stream_id, range).await?;
+    events.into_iter().map(decode_event_read).collect()
 }
 
-pub async fn process_patient_command(
-    store: EventStoreSQLXSqlite,
+pub type PatientHandlerResult =
+    HandlerResult<Option<Patient>, PatientEvent, PatientEventMeta, EventVersion>;
+
+pub async fn process_patient_command<S, Snap>(
+    store: S,
+    snapshots: Snap,
     patient_command: &PatientCommand,
-) -> Result<Vec<EventRead<PatientEvent, PatientEvent, EventVersion>>> {
+    metadata: &PatientEventMeta,
+) -> Result<PatientHandlerResult>
+where
+    S: EventStore<Value, PatientEventMeta, EventVersion> + Sync,
+    Snap: SnapshotStore<Option<Patient>, EventVersion> + Sync,
+{
     let stream_id = StreamId::from(patient_command.clone());
 
-    let events_read_range = EventsReadRange::from(patient_command.clone());
+    let expected_version = ExpectedVersion::from(patient_command.clone());
 
-    make_handler(
+    // Patient events are stored as JSON and decoded by name
+    make_handler::<_, _, _, _, _, _, Value, _, _>(
         &PATIENT_AGGREGATE,
         &store,
+        &snapshots,
         &patient_command,
+        metadata,
         &stream_id,
-        &events_read_range,
-        &ExpectedVersion::Any,
+        &expected_version,
+    )
+    .await
+}
+
+// Appends the command's events and folds them into the read model in the same call,
+// so a following get_patients already sees the write. If that projection fails the
+// events are still stored and the background projector picks them up.
+pub async fn process_and_project_patient_command(
+    app: &AppHandle,
+    store: PiiEventStore,
+    snapshots: SqliteSnapshotStore,
+    read_pool: Pool<Sqlite>,
+    patient_command: &PatientCommand,
+    metadata: &PatientEventMeta,
+) -> Result<PatientHandlerResult> {
+    let patient_meta = PatientMeta::from(patient_command.clone());
+    let res = process_patient_command(store, snapshots, patient_command, metadata).await?;
+    match process_patient_events(
+        read_pool,
+        patient_meta.id,
+        patient_meta.stream_id.clone(),
+        res.events.clone(),
     )
     .await
+    {
+        Ok(notifications) => emit_patient_notifications(app, &notifications),
+        Err(error) => log::warn!(
+            "Inline projection of {} failed, leaving it to the projector: {:#}",
+            patient_meta.stream_id,
+            error
+        ),
+    }
+    Ok(res)
+}
+
+// Appends PatientErased, then shreds the patient: the key is destroyed so every PII
+// field in the stream reads as erased, and its snapshots go too.
+// The event log itself is left untouched.
+pub async fn erase_patient(
+    app: &AppHandle,
+    store: PiiEventStore,
+    snapshots: SqliteSnapshotStore,
+    read_pool: Pool<Sqlite>,
+    patient_command: &PatientCommand,
+    metadata: &PatientEventMeta,
+) -> Result<PatientHandlerResult> {
+    let stream_id = StreamId::from(patient_command.clone());
+    if store.holds_clear_text_pii(&stream_id).await? {
+        return Err(CommandError::not_erasable(format!(
+            "Patient stream {} has events written before encryption, so its PII cannot be erased",
+            stream_id
+        ))
+        .into());
+    }
+    let res = process_and_project_patient_command(
+        app,
+        store.clone(),
+        snapshots.clone(),
+        read_pool.clone(),
+        patient_command,
+        metadata,
+    )
+    .await;
+    // Shredding is idempotent, so retrying an erase whose event is already in still finishes it
+    if let Err(error) = &res {
+        if !matches!(
+            error.downcast_ref::<CommandError>(),
+            Some(CommandError::NotUpdated { .. })
+        ) {
+            return res;
+        }
+    }
+    finish_erasure(&store, &snapshots, &read_pool, &stream_id).await?;
+    res
+}
+
+// The shredding half of an erase. It is idempotent, so the projector and startup can
+// call it again for any stream whose PatientErased went in before a crash cut it short.
+pub async fn finish_erasure(
+    store: &PiiEventStore,
+    snapshots: &SqliteSnapshotStore,
+    read_pool: &Pool<Sqlite>,
+    stream_id: &str,
+) -> Result<()> {
+    let shredded = store.shred(stream_id).await?;
+    snapshots.delete(stream_id).await?;
+    // The read rows were overwritten with erased values when PatientErased was projected
+    checkpoint_wal(read_pool).await?;
+    if shredded {
+        log::info!("Erased patient stream {}", stream_id);
+    }
+    Ok(())
+}
+
+// Startup sweep over the read model for erasures that never got to destroy their key
+pub async fn finish_interrupted_erasures(
+    store: &PiiEventStore,
+    snapshots: &SqliteSnapshotStore,
+    read_pool: Pool<Sqlite>,
+) -> Result<()> {
+    for stream_id in get_erased_stream_ids(read_pool.clone()).await? {
+        finish_erasure(store, snapshots, &read_pool, &stream_id).await?;
+    }
+    Ok(())
 }
 
 pub async fn process_patient_events(
     read_pool: Pool<Sqlite>,
     patient_id: Uuid,
     patient_stream_id: String,
-    read_events: Vec<EventRead<PatientEvent, PatientEvent, EventVersion>>,
-) -> Result<()> {
+    read_events: Vec<PatientEventRead>,
+) -> Result<Vec<PatientNotification>> {
     let patient_db = sqlx::query_as::<_, PatientDB>("SELECT * from Patient WHERE id = ? LIMIT 1")
         .bind(patient_id)
         .fetch_optional(&read_pool)
@@ -82,37 +191,81 @@ pub async fn process_patient_events(
             .fetch_optional(&read_pool)
             .await?;
 
-    let patient_state: Option<Patient> = match &patient_db {
-        Some(p) => Some(Patient {
+    // Both rows are written in one transaction, so a missing Address means the read model
+    // is damaged; fail this stream (a rebuild repairs it) rather than the whole projector
+    let patient_state: Option<Patient> = match (&patient_db, address_db) {
+        (Some(p), Some(address)) => Some(Patient {
             id: p.clone().id,
             name: p.clone().name,
             address: Address {
-                street: address_db.clone().unwrap().street,
-                city: address_db.clone().unwrap().city,
-                state: address_db.clone().unwrap().state,
-                zip: address_db.clone().unwrap().zip,
+                street: address.street,
+                city: address.city,
+                state: address.state,
+                zip: address.zip,
             },
             age: p.clone().age,
             phone: p.clone().phone,
             email: p.clone().email,
+            archived: p.archived,
+            erased: p.erased,
         }),
-        None => None,
+        (Some(p), None) => {
+            return Err(CommandError::Storage {
+                message: format!("Patient {} has no Address row in the read model", p.id),
+            }
+            .into())
+        }
+        (None, _) => None,
     };
-    let patient_updated_state = read_events
-        .iter()
-        .fold(patient_state, |a, b| PATIENT_AGGREGATE.apply(a, &b.data));
-
-    println!(
-        "version {:#?}",
-        read_events
-            .last()
-            .map_or_else(|| 0, |event| event.version.0)
-    );
-
-    println!("patient_updated_state: {:#?}", patient_updated_state);
+
+    // Delivery is at least once (inline projection and the projector), so only events
+    // newer than the stored row are applied, and only when they follow it directly
+    let stored_version = patient_db.as_ref().map_or(0, |p| p.version);
+    let read_events: Vec<PatientEventRead> = read_events
+        .into_iter()
+        .filter(|event| event.version.0 > stored_version)
+        .collect();
+    match read_events.first() {
+        None => {
+            log::info!(
+                "Skipping duplicate events for {} at version {}",
+                patient_stream_id,
+                stored_version
+            );
+            return Ok(vec![]);
+        }
+        Some(first) if first.version.0 != stored_version + 1 => {
+            log::warn!(
+                "Skipping out of order events for {}: got version {}, stored version {}",
+                patient_stream_id,
+                first.version.0,
+                stored_version
+            );
+            return Ok(vec![]);
+        }
+        Some(_) => {}
+    }
+
+    let mut patient_updated_state = patient_state;
+    let mut notifications = Vec::with_capacity(read_events.len());
+    for event in &read_events {
+        let next_state = PATIENT_AGGREGATE.apply(patient_updated_state.clone(), &event.data);
+        notifications.push(PatientNotification {
+            name: patient_notification_name(&event.data),
+            patient_id,
+            stream_id: patient_stream_id.clone(),
+            version: event.version.0,
+            changed_fields: diff_patients(&patient_updated_state, &next_state)
+                .into_iter()
+                .map(|change| change.field)
+                .collect(),
+        });
+        patient_updated_state = next_state;
+    }
+
     match patient_updated_state {
         Some(p) => {
-            upsert_patient(
+            let committed = upsert_patient(
                 read_pool,
                 p,
                 read_events
@@ -121,18 +274,629 @@ pub async fn process_patient_events(
                 patient_stream_id,
             )
             .await?;
+            // Only what this call actually committed is announced
+            if !committed {
+                return Ok(vec![]);
+            }
         }
         None => {
-            bail!("Patient not found");
+            return Err(CommandError::not_found("Patient not found").into());
         }
     }
-    Ok(())
+    Ok(notifications)
 }
 
-pub async fn get_patient_meta(read_pool: Pool<Sqlite>) -> Result<Option<PatientMeta>> {
-    let patient_meta =
-        sqlx::query_as::<_, PatientMeta>("SELECT id, stream_id, version from Patient LIMIT 1")
-            .fetch_optional(&read_pool)
-            .await?;
-    Ok(patient_meta)
+pub fn emit_patient_notifications(app: &AppHandle, notifications: &[PatientNotification]) {
+    for notification in notifications {
+        if let Err(error) = app.emit(notification.name, notification) {
+            log::warn!("Emitting {} failed: {}", notification.name, error);
+        }
+    }
+}
+
+// Patient and Address are derived data, so they can always be thrown away and
+// refolded from the patient-* streams. Returns the number of patients rebuilt.
+pub async fn rebuild_read_model<S>(store: S, read_pool: Pool<Sqlite>) -> Result<usize>
+where
+    S: EventStore<Value, PatientEventMeta, EventVersion> + Sync,
+{
+    let streams = EventStore::<Value, PatientEventMeta, EventVersion>::get_streams(
+        &store,
+        &StreamsReadFilter::StartsWith(PATIENT_STREAM_PREFIX.to_string()),
+    )
+    .await?;
+
+    // Cleared and refolded in one transaction, so readers keep the old rows until it
+    // commits
+    let mut tx = read_pool.begin().await?;
+    clear_read_model(&mut tx).await?;
+    let mut rebuilt = 0;
+    for stream in streams {
+        // One broken stream must not hold back the others
+        let events =
+            match read_patient_events(&store, &stream.id, &EventsReadRange::AllEvents).await {
+                Ok(events) => events,
+                Err(error) => {
+                    log::error!("Skipping stream {} in rebuild: {:#}", stream.id, error);
+                    continue;
+                }
+            };
+        let patient_state = events.iter().fold(PATIENT_AGGREGATE.init(), |a, b| {
+            PATIENT_AGGREGATE.apply(a, &b.data)
+        });
+        let version = events.last().map_or_else(|| 0, |event| event.version.0);
+        match patient_state {
+            Some(p) => {
+                write_patient(&mut tx, p, version, stream.id.clone()).await?;
+                rebuilt += 1;
+            }
+            None => log::warn!("Skipping stream {} without patient state", stream.id),
+        }
+    }
+    tx.commit().await?;
+    log::info!("Rebuilt read model with {} patients", rebuilt);
+    Ok(rebuilt)
+}
+
+// Every event of a patient's stream with the fields it changed, oldest first
+pub async fn get_patient_history<S>(store: S, patient_id: Uuid) -> Result<Vec<PatientHistoryEntry>>
+where
+    S: EventStore<Value, PatientEventMeta, EventVersion> + Sync,
+{
+    let events = read_patient_events(
+        &store,
+        &patient_stream_id(&patient_id),
+        &EventsReadRange::AllEvents,
+    )
+    .await?;
+    if events.is_empty() {
+        return Err(CommandError::not_found("Patient not found").into());
+    }
+
+    let mut state = PATIENT_AGGREGATE.init();
+    let mut history = Vec::with_capacity(events.len());
+    for event in events {
+        let next_state = PATIENT_AGGREGATE.apply(state.clone(), &event.data);
+        history.push(PatientHistoryEntry {
+            version: event.version.0,
+            event_name: event.name,
+            timestamp: event.created_utc,
+            metadata: event.metadata,
+            changes: diff_patients(&state, &next_state),
+        });
+        state = next_state;
+    }
+    Ok(history)
+}
+
+// Folds the stream only up to `as_of`, giving the patient exactly as it was then
+pub async fn get_patient_as_of<S>(store: S, patient_id: Uuid, as_of: AsOf) -> Result<PatientAsOf>
+where
+    S: EventStore<Value, PatientEventMeta, EventVersion> + Sync,
+{
+    let stream_id = patient_stream_id(&patient_id);
+    let events = match &as_of {
+        AsOf::Version(version) => {
+            read_patient_events(
+                &store,
+                &stream_id,
+                &EventsReadRange::ToVersion(EventVersion(*version)),
+            )
+            .await?
+        }
+        AsOf::Timestamp(timestamp) => {
+            read_patient_events(&store, &stream_id, &EventsReadRange::AllEvents)
+                .await?
+                .into_iter()
+                .take_while(|event| event.created_utc <= *timestamp)
+                .collect()
+        }
+    };
+
+    let patient_state = events.iter().fold(PATIENT_AGGREGATE.init(), |a, b| {
+        PATIENT_AGGREGATE.apply(a, &b.data)
+    });
+    match (patient_state, events.last()) {
+        (Some(patient), Some(last)) => Ok(PatientAsOf {
+            version: last.version.0,
+            timestamp: last.created_utc,
+            patient,
+        }),
+        _ => Err(CommandError::not_found(format!(
+            "Patient {} did not exist as of {:?}",
+            patient_id, as_of
+        ))
+        .into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::in_memory_store::InMemoryEventStore;
+    use crate::pii_store::PatientKeyStore;
+    use crate::snapshot_store::NoSnapshots;
+    use crate::test_support::{
+        add_patient, memory_read_pool, memory_write_pool, sqlite_event_store, test_address,
+        test_meta, update_address, update_patient,
+    };
+    use crate::types::event_meta::CommandContext;
+    use crate::types::events::{PATIENT_ADDED, PATIENT_ADDRESS_UPDATED, PATIENT_UPDATED};
+    use crate::types::patient_history::FieldChange;
+    use crate::types::pii::ERASED_PII;
+    use cosmo_store::types::event_write::EventWrite;
+
+    async fn run(
+        store: &InMemoryEventStore,
+        command: &PatientCommand,
+    ) -> Result<PatientHandlerResult> {
+        process_patient_command(store.clone(), NoSnapshots, command, &test_meta()).await
+    }
+
+    async fn read_patient_events_from(
+        store: &InMemoryEventStore,
+        id: Uuid,
+    ) -> Vec<PatientEventRead> {
+        let events: Vec<EventRead<Value, PatientEventMeta, EventVersion>> = store
+            .get_events(&patient_stream_id(&id), &EventsReadRange::AllEvents)
+            .await
+            .unwrap();
+        events
+            .into_iter()
+            .map(decode_event_read)
+            .collect::<Result<_>>()
+            .unwrap()
+    }
+
+    fn change(field: &str, before: Option<&str>, after: Option<&str>) -> FieldChange {
+        FieldChange {
+            field: field.to_string(),
+            before: before.map(str::to_string),
+            after: after.map(str::to_string),
+        }
+    }
+
+    fn new_address() -> Address {
+        Address {
+            street: "9 Elm St".to_string(),
+            city: "Shelbyville".to_string(),
+            state: "IL".to_string(),
+            zip: "62565".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn add_update_and_change_address() {
+        let id = Uuid::new_v4();
+        let store = InMemoryEventStore::new();
+
+        let added = run(&store, &add_patient(id)).await.unwrap();
+        assert_eq!(added.events.last().unwrap().version, EventVersion(1));
+
+        let updated = run(&store, &update_patient(id, 1, "John Doe"))
+            .await
+            .unwrap();
+        assert_eq!(updated.events.last().unwrap().version, EventVersion(2));
+
+        let moved = run(&store, &update_address(id, 2, new_address()))
+            .await
+            .unwrap();
+        assert_eq!(moved.events.last().unwrap().version, EventVersion(3));
+
+        let patient = moved.state.unwrap();
+        assert_eq!(patient.id, id);
+        assert_eq!(patient.name, "John Doe");
+        assert_eq!(patient.address, new_address());
+
+        let stored = read_patient_events_from(&store, id).await;
+        assert_eq!(stored.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn update_of_unknown_patient_is_not_found() {
+        let store = InMemoryEventStore::new();
+
+        let error = run(&store, &update_patient(Uuid::new_v4(), 0, "John Doe"))
+            .await
+            .unwrap_err();
+
+        assert_eq!(CommandError::from(error).code(), "not_found");
+    }
+
+    #[tokio::test]
+    async fn address_update_of_unknown_patient_is_not_found() {
+        let store = InMemoryEventStore::new();
+
+        let error = run(&store, &update_address(Uuid::new_v4(), 0, new_address()))
+            .await
+            .unwrap_err();
+
+        assert_eq!(CommandError::from(error).code(), "not_found");
+    }
+
+    #[tokio::test]
+    async fn second_window_on_the_same_version_gets_a_concurrency_conflict() {
+        let id = Uuid::new_v4();
+        let store = InMemoryEventStore::new();
+        run(&store, &add_patient(id)).await.unwrap();
+
+        run(&store, &update_patient(id, 1, "John Doe"))
+            .await
+            .unwrap();
+        let error = run(&store, &update_address(id, 1, new_address()))
+            .await
+            .unwrap_err();
+
+        match CommandError::from(error) {
+            CommandError::ConcurrencyConflict(conflict) => {
+                assert_eq!(conflict.stream_id, patient_stream_id(&id));
+                assert_eq!(conflict.expected_version, 1);
+                assert_eq!(conflict.current_version, 2);
+            }
+            other => panic!("expected a concurrency conflict, got {:?}", other),
+        }
+        assert_eq!(read_patient_events_from(&store, id).await.len(), 2);
+    }
+
+    fn read_row(id: Uuid, erased: bool) -> Patient {
+        Patient {
+            id,
+            name: ERASED_PII.to_string(),
+            address: Address::erased(),
+            age: 40,
+            phone: ERASED_PII.to_string(),
+            email: ERASED_PII.to_string(),
+            archived: false,
+            erased,
+        }
+    }
+
+    #[tokio::test]
+    async fn startup_shreds_the_key_of_an_interrupted_erasure() {
+        let write_pool = memory_write_pool().await;
+        let read_pool = memory_read_pool().await;
+        let keys = PatientKeyStore::new(write_pool.clone());
+        let store = PiiEventStore::new(sqlite_event_store(&write_pool).await, keys.clone());
+        let snapshots = SqliteSnapshotStore::new(write_pool, 1, keys.clone());
+        // Erased in the read model, but the crash came before the key was destroyed
+        let (erased, kept) = (Uuid::new_v4(), Uuid::new_v4());
+        for (id, is_erased) in [(erased, true), (kept, false)] {
+            keys.get_or_create_key(&patient_stream_id(&id))
+                .await
+                .unwrap();
+            upsert_patient(
+                read_pool.clone(),
+                read_row(id, is_erased),
+                2,
+                patient_stream_id(&id),
+            )
+            .await
+            .unwrap();
+        }
+
+        finish_interrupted_erasures(&store, &snapshots, read_pool)
+            .await
+            .unwrap();
+
+        assert!(keys
+            .key(&patient_stream_id(&erased))
+            .await
+            .unwrap()
+            .is_none());
+        assert!(keys.key(&patient_stream_id(&kept)).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn rebuild_skips_a_stream_that_cannot_be_read() {
+        let store = InMemoryEventStore::new();
+        let read_pool = memory_read_pool().await;
+        run(&store, &add_patient(Uuid::new_v4())).await.unwrap();
+        let broken = EventWrite {
+            id: Uuid::new_v4(),
+            correlation_id: None,
+            causation_id: None,
+            name: "NotAPatientEvent".to_string(),
+            data: Value::Null,
+            metadata: Some(test_meta()),
+        };
+        store
+            .append_event(
+                &patient_stream_id(&Uuid::new_v4()),
+                &ExpectedVersion::NoStream,
+                &broken,
+            )
+            .await
+            .unwrap();
+
+        let rebuilt = rebuild_read_model(store, read_pool.clone()).await.unwrap();
+
+        assert_eq!(rebuilt, 1);
+        let rows: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM Patient")
+            .fetch_one(&read_pool)
+            .await
+            .unwrap();
+        assert_eq!(rows, 1);
+    }
+
+    // Exact is compared against the SQLite store's own check as well as ours; this pins
+    // the real one so a mismatch cannot turn every update into an internal error
+    #[tokio::test]
+    async fn add_then_update_against_the_sqlite_store() {
+        let id = Uuid::new_v4();
+        let write_pool = memory_write_pool().await;
+        let store = sqlite_event_store(&write_pool).await;
+
+        process_patient_command(store.clone(), NoSnapshots, &add_patient(id), &test_meta())
+            .await
+            .unwrap();
+        let updated = process_patient_command(
+            store.clone(),
+            NoSnapshots,
+            &update_patient(id, 1, "John Doe"),
+            &test_meta(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(updated.events.last().unwrap().version, EventVersion(2));
+        assert_eq!(updated.state.unwrap().name, "John Doe");
+
+        let error = process_patient_command(
+            store,
+            NoSnapshots,
+            &update_address(id, 1, test_address()),
+            &test_meta(),
+        )
+        .await
+        .unwrap_err();
+        match CommandError::from(error) {
+            CommandError::ConcurrencyConflict(conflict) => {
+                assert_eq!(conflict.expected_version, 1);
+                assert_eq!(conflict.current_version, 2);
+            }
+            other => panic!("expected a concurrency conflict, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn history_lists_field_changes_and_metadata_per_event() {
+        let id = Uuid::new_v4();
+        let store = InMemoryEventStore::new();
+        run(&store, &add_patient(id)).await.unwrap();
+        let context = CommandContext {
+            correlation_id: Some(Uuid::new_v4()),
+            causation_id: None,
+            user: Some("dr.jones".to_string()),
+        };
+        let meta = PatientEventMeta::new(Some(context.clone()), Uuid::nil());
+        process_patient_command(
+            store.clone(),
+            NoSnapshots,
+            &update_patient(id, 1, "John Doe"),
+            &meta,
+        )
+        .await
+        .unwrap();
+        run(&store, &update_address(id, 2, new_address()))
+            .await
+            .unwrap();
+
+        let history = get_patient_history(store, id).await.unwrap();
+
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[0].event_name, PATIENT_ADDED);
+        assert!(history[0]
+            .changes
+            .contains(&change("name", None, Some("Jane Doe"))));
+
+        assert_eq!(history[1].version, 2);
+        assert_eq!(history[1].event_name, PATIENT_UPDATED);
+        assert_eq!(
+            history[1].changes,
+            vec![
+                change("name", Some("Jane Doe"), Some("John Doe")),
+                change("age", Some("40"), Some("41")),
+                change("phone", Some("555-0100"), Some("555-0101")),
+                change(
+                    "email",
+                    Some("jane@example.com"),
+                    Some("jane.doe@example.com")
+                ),
+            ]
+        );
+        let metadata = history[1].metadata.as_ref().unwrap();
+        assert_eq!(metadata.user, context.user);
+        assert_eq!(Some(metadata.correlation_id), context.correlation_id);
+
+        assert_eq!(history[2].event_name, PATIENT_ADDRESS_UPDATED);
+        assert_eq!(
+            history[2].changes,
+            vec![
+                change("address.street", Some("1 Main St"), Some("9 Elm St")),
+                change("address.city", Some("Springfield"), Some("Shelbyville")),
+                change("address.zip", Some("62701"), Some("62565")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn as_of_a_timestamp_folds_only_the_events_up_to_it() {
+        let id = Uuid::new_v4();
+        let store = InMemoryEventStore::new();
+        let added = run(&store, &add_patient(id)).await.unwrap();
+        let added_at = added.events[0].created_utc;
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        run(&store, &update_patient(id, 1, "John Doe"))
+            .await
+            .unwrap();
+
+        let then = get_patient_as_of(store.clone(), id, AsOf::Timestamp(added_at))
+            .await
+            .unwrap();
+        assert_eq!(then.version, 1);
+        assert_eq!(then.timestamp, added_at);
+        assert_eq!(then.patient.name, "Jane Doe");
+
+        let now = get_patient_as_of(store.clone(), id, AsOf::Timestamp(chrono::Utc::now()))
+            .await
+            .unwrap();
+        assert_eq!(now.version, 2);
+        assert_eq!(now.patient.name, "John Doe");
+
+        let before = AsOf::Timestamp(added_at - chrono::Duration::seconds(1));
+        let error = get_patient_as_of(store, id, before).await.unwrap_err();
+        assert_eq!(CommandError::from(error).code(), "not_found");
+    }
+
+    #[tokio::test]
+    async fn patient_row_without_an_address_is_an_error_not_a_panic() {
+        let id = Uuid::new_v4();
+        let store = InMemoryEventStore::new();
+        let read_pool = memory_read_pool().await;
+        run(&store, &add_patient(id)).await.unwrap();
+        run(&store, &update_patient(id, 1, "John Doe"))
+            .await
+            .unwrap();
+        let events = read_patient_events_from(&store, id).await;
+        process_patient_events(
+            read_pool.clone(),
+            id,
+            patient_stream_id(&id),
+            events[..1].to_vec(),
+        )
+        .await
+        .unwrap();
+        sqlx::query("DELETE FROM Address")
+            .execute(&read_pool)
+            .await
+            .unwrap();
+
+        let error =
+            process_patient_events(read_pool, id, patient_stream_id(&id), events[1..].to_vec())
+                .await
+                .unwrap_err();
+
+        assert_eq!(CommandError::from(error).code(), "storage");
+    }
+
+    async fn stored_row(read_pool: &Pool<Sqlite>, id: Uuid) -> (PatientDB, i64) {
+        let row = sqlx::query_as::<_, PatientDB>("SELECT * FROM Patient WHERE id = ?")
+            .bind(id)
+            .fetch_one(read_pool)
+            .await
+            .unwrap();
+        let checkpoint: i64 =
+            sqlx::query_scalar("SELECT version FROM ProjectionCheckpoint WHERE stream_id = ?")
+                .bind(patient_stream_id(&id))
+                .fetch_one(read_pool)
+                .await
+                .unwrap();
+        (row, checkpoint)
+    }
+
+    // Three events: added (1), updated to John Doe (2), moved to new_address (3)
+    async fn three_events(store: &InMemoryEventStore, id: Uuid) -> Vec<PatientEventRead> {
+        run(store, &add_patient(id)).await.unwrap();
+        run(store, &update_patient(id, 1, "John Doe"))
+            .await
+            .unwrap();
+        run(store, &update_address(id, 2, new_address()))
+            .await
+            .unwrap();
+        read_patient_events_from(store, id).await
+    }
+
+    #[tokio::test]
+    async fn replaying_a_projected_batch_changes_nothing() {
+        let id = Uuid::new_v4();
+        let read_pool = memory_read_pool().await;
+        let events = three_events(&InMemoryEventStore::new(), id).await;
+        let first = process_patient_events(
+            read_pool.clone(),
+            id,
+            patient_stream_id(&id),
+            events[..2].to_vec(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(first.len(), 2);
+
+        let replayed = process_patient_events(
+            read_pool.clone(),
+            id,
+            patient_stream_id(&id),
+            events[..2].to_vec(),
+        )
+        .await
+        .unwrap();
+
+        assert!(replayed.is_empty());
+        let (row, checkpoint) = stored_row(&read_pool, id).await;
+        assert_eq!(row.version, 2);
+        assert_eq!(row.name, "John Doe");
+        assert_eq!(checkpoint, 2);
+    }
+
+    #[tokio::test]
+    async fn a_batch_after_a_gap_is_skipped() {
+        let id = Uuid::new_v4();
+        let read_pool = memory_read_pool().await;
+        let events = three_events(&InMemoryEventStore::new(), id).await;
+        process_patient_events(
+            read_pool.clone(),
+            id,
+            patient_stream_id(&id),
+            events[..1].to_vec(),
+        )
+        .await
+        .unwrap();
+
+        let skipped = process_patient_events(
+            read_pool.clone(),
+            id,
+            patient_stream_id(&id),
+            events[2..].to_vec(),
+        )
+        .await
+        .unwrap();
+
+        assert!(skipped.is_empty());
+        let (row, checkpoint) = stored_row(&read_pool, id).await;
+        assert_eq!(row.version, 1);
+        assert_eq!(row.name, "Jane Doe");
+        assert_eq!(checkpoint, 1);
+    }
+
+    #[tokio::test]
+    async fn an_older_version_never_overwrites_a_newer_row() {
+        let id = Uuid::new_v4();
+        let store = InMemoryEventStore::new();
+        let read_pool = memory_read_pool().await;
+        let added = run(&store, &add_patient(id)).await.unwrap();
+        let updated = run(&store, &update_patient(id, 1, "John Doe"))
+            .await
+            .unwrap();
+        upsert_patient(
+            read_pool.clone(),
+            updated.state.unwrap(),
+            2,
+            patient_stream_id(&id),
+        )
+        .await
+        .unwrap();
+
+        let committed = upsert_patient(
+            read_pool.clone(),
+            added.state.unwrap(),
+            1,
+            patient_stream_id(&id),
+        )
+        .await
+        .unwrap();
+
+        assert!(!committed);
+        let (row, checkpoint) = stored_row(&read_pool, id).await;
+        assert_eq!(row.version, 2);
+        assert_eq!(row.name, "John Doe");
+        assert_eq!(checkpoint, 2);
+    }
 }
@@ -0,0 +1,533 @@
+use crate::db_helpers::checkpoint_wal;
+use crate::types::errors::CommandError;
+use crate::types::pii::{erased_pii_value, PII_FIELDS};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use base64::prelude::*;
+use chrono::Utc;
+use cosmo_store::common::i64_event_version::EventVersion;
+use cosmo_store::traits::event_store::EventStore;
+use cosmo_store::types::event_read::EventRead;
+use cosmo_store::types::event_read_range::EventsReadRange;
+use cosmo_store::types::event_write::EventWrite;
+use cosmo_store::types::expected_version::ExpectedVersion;
+use cosmo_store::types::stream::Stream;
+use cosmo_store::types::stream_read_filter::StreamsReadFilter;
+use cosmo_store_sqlx_sqlite::event_store_sqlx_sqlite::EventStoreSQLXSqlite;
+use serde_json::{Map, Value};
+use sqlx::{Pool, Sqlite};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+// A PII field in the store is replaced by {"sealed": base64(nonce || ciphertext)}
+const SEALED: &str = "sealed";
+const NONCE_LEN: usize = 12;
+
+// One AES-256-GCM key per patient stream, kept in the `patient_keys` table of write.db
+// rather than next to the events. Destroying the key is what erases the patient.
+#[derive(Clone, Debug)]
+pub struct PatientKeyStore {
+    pool: Pool<Sqlite>,
+}
+
+impl PatientKeyStore {
+    pub fn new(pool: Pool<Sqlite>) -> Self {
+        PatientKeyStore { pool }
+    }
+
+    pub async fn key(&self, stream_id: &str) -> Result<Option<Aes256Gcm>> {
+        let key: Option<Vec<u8>> =
+            sqlx::query_scalar("SELECT key FROM patient_keys WHERE stream_id = $1")
+                .bind(stream_id)
+                .fetch_optional(&self.pool)
+                .await?;
+        match key {
+            Some(key) => {
+                Ok(Some(Aes256Gcm::new_from_slice(&key).map_err(|_| {
+                    anyhow!("Invalid key stored for {}", stream_id)
+                })?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // A new stream gets its key here, and so does a stream written before encryption.
+    // A destroyed key is never replaced: events sealed under it would become undecryptable
+    // instead of erased, so appending to an erased stream fails.
+    pub async fn get_or_create_key(&self, stream_id: &str) -> Result<Aes256Gcm> {
+        let key = Aes256Gcm::generate_key(OsRng);
+        // A concurrent writer may have created it first, so the stored key always wins.
+        // One statement, so a destroy cannot slip in between the check and the insert.
+        sqlx::query("INSERT INTO patient_keys (stream_id, key, created_utc) SELECT $1, $2, $3 WHERE NOT EXISTS (SELECT 1 FROM destroyed_patient_keys WHERE stream_id = $1) ON CONFLICT(stream_id) DO NOTHING")
+            .bind(stream_id)
+            .bind(key.as_slice())
+            .bind(Utc::now())
+            .execute(&self.pool)
+            .await?;
+        match self.key(stream_id).await? {
+            Some(key) => Ok(key),
+            None => Err(CommandError::erased(format!(
+                "Patient stream {} has been erased",
+                stream_id
+            ))
+            .into()),
+        }
+    }
+
+    // Returns false when there was no key left to destroy
+    pub async fn destroy_key(&self, stream_id: &str) -> Result<bool> {
+        let mut tx = self.pool.begin().await?;
+        let deleted = sqlx::query("DELETE FROM patient_keys WHERE stream_id = $1")
+            .bind(stream_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("INSERT INTO destroyed_patient_keys (stream_id, destroyed_utc) VALUES ($1, $2) ON CONFLICT(stream_id) DO NOTHING")
+            .bind(stream_id)
+            .bind(Utc::now())
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        checkpoint_wal(&self.pool).await?;
+        Ok(deleted.rows_affected() > 0)
+    }
+}
+
+fn seal_value(cipher: &Aes256Gcm, field: &str, value: &Value) -> Result<Value> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let plaintext = serde_json::to_vec(value)?;
+    // The field name is authenticated too, so sealed values cannot be swapped around
+    let ciphertext = cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: &plaintext,
+                aad: field.as_bytes(),
+            },
+        )
+        .map_err(|_| anyhow!("Encrypting {} failed", field))?;
+    let mut sealed = nonce.to_vec();
+    sealed.extend_from_slice(&ciphertext);
+
+    let mut object = Map::new();
+    object.insert(
+        SEALED.to_string(),
+        Value::String(BASE64_STANDARD.encode(sealed)),
+    );
+    Ok(Value::Object(object))
+}
+
+fn unseal_value(cipher: Option<&Aes256Gcm>, field: &str, value: &Value) -> Result<Value> {
+    // Fields written before encryption are still in clear text and pass through as is
+    let Some(sealed) = value.get(SEALED).and_then(Value::as_str) else {
+        return Ok(value.clone());
+    };
+    let Some(cipher) = cipher else {
+        return Ok(erased_pii_value(field));
+    };
+    let sealed = BASE64_STANDARD.decode(sealed)?;
+    if sealed.len() < NONCE_LEN {
+        bail!("Sealed {} is too short", field);
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let plaintext = cipher
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: field.as_bytes(),
+            },
+        )
+        .map_err(|_| anyhow!("Decrypting {} failed", field))?;
+    Ok(serde_json::from_slice(&plaintext)?)
+}
+
+fn map_pii_fields(data: &Value, f: impl Fn(&str, &Value) -> Result<Value>) -> Result<Value> {
+    let mut data = data.clone();
+    if let Some(object) = data.as_object_mut() {
+        for field in PII_FIELDS {
+            if let Some(value) = object.get_mut(field) {
+                *value = f(field, value)?;
+            }
+        }
+    }
+    Ok(data)
+}
+
+// Seals the PII fields of any payload shaped like the patient events (snapshots too)
+pub fn seal_pii(cipher: &Aes256Gcm, data: &Value) -> Result<Value> {
+    map_pii_fields(data, |field, value| seal_value(cipher, field, value))
+}
+
+pub fn unseal_pii(cipher: Option<&Aes256Gcm>, data: &Value) -> Result<Value> {
+    map_pii_fields(data, |field, value| unseal_value(cipher, field, value))
+}
+
+fn seal_write<Meta: Clone>(
+    cipher: &Aes256Gcm,
+    event: &EventWrite<Value, Meta>,
+) -> Result<EventWrite<Value, Meta>> {
+    Ok(EventWrite {
+        id: event.id,
+        correlation_id: event.correlation_id,
+        causation_id: event.causation_id,
+        name: event.name.clone(),
+        data: seal_pii(cipher, &event.data)?,
+        metadata: event.metadata.clone(),
+    })
+}
+
+// Without a cipher the patient has been erased and every sealed field reads as erased
+fn unseal_read<Meta>(
+    cipher: Option<&Aes256Gcm>,
+    event: EventRead<Value, Meta, EventVersion>,
+) -> Result<EventRead<Value, Meta, EventVersion>> {
+    let data = unseal_pii(cipher, &event.data)?;
+    Ok(EventRead { data, ..event })
+}
+
+// Wraps the SQLite store so PII never reaches the events table in clear text. Everything
+// above it (handler, aggregate, projections) keeps seeing plain payloads.
+#[derive(Clone)]
+pub struct PiiEventStore {
+    inner: EventStoreSQLXSqlite,
+    keys: PatientKeyStore,
+}
+
+impl PiiEventStore {
+    pub fn new(inner: EventStoreSQLXSqlite, keys: PatientKeyStore) -> Self {
+        PiiEventStore { inner, keys }
+    }
+
+    // Crypto-shredding: the events stay, but their PII can no longer be decrypted
+    pub async fn shred(&self, stream_id: &str) -> Result<bool> {
+        self.keys.destroy_key(stream_id).await
+    }
+
+    // Events written before encryption carry their PII in clear text, which destroying
+    // the key cannot reach
+    pub async fn holds_clear_text_pii(&self, stream_id: &str) -> Result<bool> {
+        let events: Vec<EventRead<Value, Value, EventVersion>> = self
+            .inner
+            .get_events(stream_id, &EventsReadRange::AllEvents)
+            .await?;
+        Ok(events.iter().any(|event| {
+            PII_FIELDS.iter().any(|field| {
+                event
+                    .data
+                    .get(field)
+                    .is_some_and(|value| value.get(SEALED).is_none())
+            })
+        }))
+    }
+}
+
+#[async_trait]
+impl<Meta> EventStore<Value, Meta, EventVersion> for PiiEventStore
+where
+    EventStoreSQLXSqlite: EventStore<Value, Meta, EventVersion>,
+    Meta: Clone + Send + Sync + 'static,
+{
+    async fn append_event(
+        &self,
+        stream_id: &str,
+        version: &ExpectedVersion<EventVersion>,
+        payload: &EventWrite<Value, Meta>,
+    ) -> Result<EventRead<Value, Meta, EventVersion>> {
+        let cipher = self.keys.get_or_create_key(stream_id).await?;
+        let sealed = seal_write(&cipher, payload)?;
+        let event = EventStore::<Value, Meta, EventVersion>::append_event(
+            &self.inner,
+            stream_id,
+            version,
+            &sealed,
+        )
+        .await?;
+        unseal_read(Some(&cipher), event)
+    }
+
+    async fn append_events(
+        &self,
+        stream_id: &str,
+        version: &ExpectedVersion<EventVersion>,
+        payload: Vec<EventWrite<Value, Meta>>,
+    ) -> Result<Vec<EventRead<Value, Meta, EventVersion>>> {
+        let cipher = self.keys.get_or_create_key(stream_id).await?;
+        let sealed = payload
+            .iter()
+            .map(|event| seal_write(&cipher, event))
+            .collect::<Result<Vec<_>>>()?;
+        let events = EventStore::<Value, Meta, EventVersion>::append_events(
+            &self.inner,
+            stream_id,
+            version,
+            sealed,
+        )
+        .await?;
+        events
+            .into_iter()
+            .map(|event| unseal_read(Some(&cipher), event))
+            .collect()
+    }
+
+    async fn get_event(
+        &self,
+        stream_id: &str,
+        version: &EventVersion,
+    ) -> Result<EventRead<Value, Meta, EventVersion>> {
+        let cipher = self.keys.key(stream_id).await?;
+        let event =
+            EventStore::<Value, Meta, EventVersion>::get_event(&self.inner, stream_id, version)
+                .await?;
+        unseal_read(cipher.as_ref(), event)
+    }
+
+    async fn get_events(
+        &self,
+        stream_id: &str,
+        version: &EventsReadRange<EventVersion>,
+    ) -> Result<Vec<EventRead<Value, Meta, EventVersion>>> {
+        let cipher = self.keys.key(stream_id).await?;
+        let events =
+            EventStore::<Value, Meta, EventVersion>::get_events(&self.inner, stream_id, version)
+                .await?;
+        events
+            .into_iter()
+            .map(|event| unseal_read(cipher.as_ref(), event))
+            .collect()
+    }
+
+    async fn get_events_by_correlation_id(
+        &self,
+        correlation_id: &Uuid,
+    ) -> Result<Vec<EventRead<Value, Meta, EventVersion>>> {
+        let events = EventStore::<Value, Meta, EventVersion>::get_events_by_correlation_id(
+            &self.inner,
+            correlation_id,
+        )
+        .await?;
+        // A correlation can span several patients, each with its own key
+        let mut ciphers: HashMap<String, Option<Aes256Gcm>> = HashMap::new();
+        let mut unsealed = Vec::with_capacity(events.len());
+        for event in events {
+            if !ciphers.contains_key(&event.stream_id) {
+                let cipher = self.keys.key(&event.stream_id).await?;
+                ciphers.insert(event.stream_id.clone(), cipher);
+            }
+            let cipher = ciphers.get(&event.stream_id).and_then(Option::as_ref);
+            unsealed.push(unseal_read(cipher, event)?);
+        }
+        Ok(unsealed)
+    }
+
+    async fn get_streams(&self, filter: &StreamsReadFilter) -> Result<Vec<Stream<EventVersion>>> {
+        EventStore::<Value, Meta, EventVersion>::get_streams(&self.inner, filter).await
+    }
+
+    async fn get_stream(&self, stream_id: &str) -> Result<Stream<EventVersion>> {
+        EventStore::<Value, Meta, EventVersion>::get_stream(&self.inner, stream_id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_handler::EventCodec;
+    use crate::test_support::{memory_write_pool, sqlite_event_store, test_address, test_meta};
+    use crate::types::address::Address;
+    use crate::types::commands::patient_stream_id;
+    use crate::types::event_meta::PatientEventMeta;
+    use crate::types::events::{PatientAdded, PatientEvent, PatientUpdated};
+    use crate::types::pii::ERASED_PII;
+
+    type PatientEventRead = EventRead<Value, PatientEventMeta, EventVersion>;
+
+    async fn pii_store() -> PiiEventStore {
+        let pool = memory_write_pool().await;
+        PiiEventStore::new(sqlite_event_store(&pool).await, PatientKeyStore::new(pool))
+    }
+
+    fn added(id: Uuid) -> PatientEvent {
+        PatientEvent::PatientAdded(PatientAdded {
+            id,
+            name: "Jane Doe".to_string(),
+            version: 0,
+            address: test_address(),
+            age: 40,
+            phone: "555-0100".to_string(),
+            email: "jane@example.com".to_string(),
+        })
+    }
+
+    fn updated() -> PatientEvent {
+        PatientEvent::PatientUpdated(PatientUpdated {
+            name: "John Doe".to_string(),
+            age: 41,
+            phone: "555-0101".to_string(),
+            email: "john@example.com".to_string(),
+        })
+    }
+
+    async fn append(
+        store: &PiiEventStore,
+        stream_id: &str,
+        version: ExpectedVersion<EventVersion>,
+        event: PatientEvent,
+    ) -> Result<PatientEventRead> {
+        store
+            .append_event(stream_id, &version, &event.encode(&test_meta())?)
+            .await
+    }
+
+    async fn read_all(store: &PiiEventStore, stream_id: &str) -> Vec<PatientEventRead> {
+        store
+            .get_events(stream_id, &EventsReadRange::AllEvents)
+            .await
+            .unwrap()
+    }
+
+    fn decode(event: &PatientEventRead) -> PatientEvent {
+        PatientEvent::decode(&event.name, &event.data, event.metadata.as_ref()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn the_events_table_never_sees_clear_text_pii() {
+        let store = pii_store().await;
+        let id = Uuid::new_v4();
+        let stream_id = patient_stream_id(&id);
+        append(&store, &stream_id, ExpectedVersion::NoStream, added(id))
+            .await
+            .unwrap();
+
+        let raw: Vec<PatientEventRead> = store
+            .inner
+            .get_events(&stream_id, &EventsReadRange::AllEvents)
+            .await
+            .unwrap();
+
+        let data = raw[0].data.to_string();
+        for pii in ["Jane Doe", "555-0100", "jane@example.com", "1 Main St"] {
+            assert!(!data.contains(pii), "{} stored in clear text", pii);
+        }
+        for field in PII_FIELDS {
+            assert!(raw[0].data[field][SEALED].is_string());
+        }
+        assert_eq!(raw[0].data["age"], 40);
+        assert_eq!(raw[0].data["id"], id.to_string());
+    }
+
+    #[tokio::test]
+    async fn reads_decrypt_what_was_written() {
+        let store = pii_store().await;
+        let id = Uuid::new_v4();
+        let stream_id = patient_stream_id(&id);
+        let written = append(&store, &stream_id, ExpectedVersion::NoStream, added(id))
+            .await
+            .unwrap();
+        append(
+            &store,
+            &stream_id,
+            ExpectedVersion::Exact(EventVersion(2)),
+            updated(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(decode(&written), added(id));
+        let events = read_all(&store, &stream_id).await;
+        assert_eq!(
+            events.iter().map(decode).collect::<Vec<_>>(),
+            vec![added(id), updated()]
+        );
+        let second: PatientEventRead = store.get_event(&stream_id, &EventVersion(2)).await.unwrap();
+        assert_eq!(decode(&second), updated());
+    }
+
+    #[tokio::test]
+    async fn shredded_fields_read_as_erased_and_the_rest_survives() {
+        let store = pii_store().await;
+        let id = Uuid::new_v4();
+        let stream_id = patient_stream_id(&id);
+        append(&store, &stream_id, ExpectedVersion::NoStream, added(id))
+            .await
+            .unwrap();
+
+        assert!(store.shred(&stream_id).await.unwrap());
+
+        let events = read_all(&store, &stream_id).await;
+        match decode(&events[0]) {
+            PatientEvent::PatientAdded(event) => {
+                assert_eq!(event.name, ERASED_PII);
+                assert_eq!(event.phone, ERASED_PII);
+                assert_eq!(event.email, ERASED_PII);
+                assert_eq!(event.address, Address::erased());
+                assert_eq!(event.age, 40);
+                assert_eq!(event.id, id);
+            }
+            other => panic!("expected PatientAdded, got {:?}", other),
+        }
+        assert_eq!(events[0].stream_id, stream_id);
+        assert!(!store.shred(&stream_id).await.unwrap());
+    }
+
+    // Regression: a writer that passed its version check before an erase used to create
+    // a fresh key on append, leaving the stream sealed under a key that no longer exists
+    #[tokio::test]
+    async fn appending_after_a_shred_never_brings_a_key_back() {
+        let store = pii_store().await;
+        let id = Uuid::new_v4();
+        let stream_id = patient_stream_id(&id);
+        append(&store, &stream_id, ExpectedVersion::NoStream, added(id))
+            .await
+            .unwrap();
+        store.shred(&stream_id).await.unwrap();
+
+        for version in [
+            ExpectedVersion::Exact(EventVersion(2)),
+            ExpectedVersion::NoStream,
+        ] {
+            let error = append(&store, &stream_id, version, updated())
+                .await
+                .unwrap_err();
+            assert_eq!(CommandError::from(error).code(), "erased");
+        }
+
+        assert!(store.keys.key(&stream_id).await.unwrap().is_none());
+        let events = read_all(&store, &stream_id).await;
+        assert_eq!(events.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn tells_streams_written_before_encryption_apart() {
+        let store = pii_store().await;
+        let (sealed, legacy) = (Uuid::new_v4(), Uuid::new_v4());
+        append(
+            &store,
+            &patient_stream_id(&sealed),
+            ExpectedVersion::NoStream,
+            added(sealed),
+        )
+        .await
+        .unwrap();
+        // Straight into the inner store, like events appended before encryption
+        store
+            .inner
+            .append_event(
+                &patient_stream_id(&legacy),
+                &ExpectedVersion::NoStream,
+                &added(legacy).encode(&test_meta()).unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert!(!store
+            .holds_clear_text_pii(&patient_stream_id(&sealed))
+            .await
+            .unwrap());
+        assert!(store
+            .holds_clear_text_pii(&patient_stream_id(&legacy))
+            .await
+            .unwrap());
+    }
+}
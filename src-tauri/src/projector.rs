@@ -0,0 +1,177 @@
+use crate::db_helpers::{get_projection_checkpoints, PATIENT_PROJECTION};
+use crate::patient_helper::{
+    emit_patient_notifications, finish_erasure, process_patient_events, read_patient_events,
+};
+use crate::pii_store::PiiEventStore;
+use crate::snapshot_store::SqliteSnapshotStore;
+use crate::types::commands::{patient_id_from_stream_id, PATIENT_STREAM_PREFIX};
+use crate::types::event_meta::PatientEventMeta;
+use crate::types::events::PatientEvent;
+use crate::types::notifications::PatientNotification;
+use anyhow::Result;
+use cosmo_store::common::i64_event_version::EventVersion;
+use cosmo_store::traits::event_store::EventStore;
+use cosmo_store::types::event_read_range::EventsReadRange;
+use cosmo_store::types::stream_read_filter::StreamsReadFilter;
+use serde_json::Value;
+use sqlx::{Pool, Sqlite};
+use std::time::Duration;
+use tauri::AppHandle;
+
+pub const PROJECTOR_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+// Keeps the Patient/Address read model caught up with the event store, independent
+// of the inline projection done by commands. Runs until the app exits.
+pub async fn run_patient_projector(
+    app: AppHandle,
+    store: PiiEventStore,
+    snapshots: SqliteSnapshotStore,
+    read_pool: Pool<Sqlite>,
+) {
+    loop {
+        match project_pending_patient_events(&app, &store, &snapshots, &read_pool).await {
+            Ok(0) => {}
+            Ok(projected) => log::info!("Projector caught up {} patient streams", projected),
+            Err(error) => log::error!("Projector failed, retrying: {:#}", error),
+        }
+        tokio::time::sleep(PROJECTOR_POLL_INTERVAL).await;
+    }
+}
+
+// The store has no global position, so the checkpoint is the last projected version
+// of each stream. It is written in the same transaction as the read rows, so after a
+// crash projection resumes exactly after the last committed event.
+pub async fn project_pending_patient_events(
+    app: &AppHandle,
+    store: &PiiEventStore,
+    snapshots: &SqliteSnapshotStore,
+    read_pool: &Pool<Sqlite>,
+) -> Result<usize> {
+    let (projected, notifications) = catch_up_patient_streams(store, snapshots, read_pool).await?;
+    emit_patient_notifications(app, &notifications);
+    Ok(projected)
+}
+
+// One pass over every patient stream behind its checkpoint. Returns how many streams
+// were projected and what they committed, for the caller to announce.
+pub async fn catch_up_patient_streams(
+    store: &PiiEventStore,
+    snapshots: &SqliteSnapshotStore,
+    read_pool: &Pool<Sqlite>,
+) -> Result<(usize, Vec<PatientNotification>)> {
+    let mut streams = EventStore::<Value, PatientEventMeta, EventVersion>::get_streams(
+        store,
+        &StreamsReadFilter::StartsWith(PATIENT_STREAM_PREFIX.to_string()),
+    )
+    .await?;
+    streams.sort_by_key(|stream| stream.last_updated_utc);
+    let checkpoints = get_projection_checkpoints(read_pool.clone(), PATIENT_PROJECTION).await?;
+
+    let mut projected = 0;
+    let mut notifications = vec![];
+    for stream in streams {
+        let checkpoint = checkpoints.get(&stream.id).copied().unwrap_or(0);
+        if stream.last_version.0 <= checkpoint {
+            continue;
+        }
+        let Some(patient_id) = patient_id_from_stream_id(&stream.id) else {
+            log::warn!(
+                "Projector skipping stream {} without a patient id",
+                stream.id
+            );
+            continue;
+        };
+        // One broken stream must not hold back the others
+        let events = match read_patient_events(
+            store,
+            &stream.id,
+            &EventsReadRange::FromVersion(EventVersion(checkpoint + 1)),
+        )
+        .await
+        {
+            Ok(events) => events,
+            Err(error) => {
+                log::error!("Projector failed to read {}: {:#}", stream.id, error);
+                continue;
+            }
+        };
+        // The erase command may have crashed between appending and shredding
+        let erased = events
+            .iter()
+            .any(|event| matches!(event.data, PatientEvent::PatientErased(_)));
+        match process_patient_events(read_pool.clone(), patient_id, stream.id.clone(), events).await
+        {
+            Ok(committed) => {
+                notifications.extend(committed);
+                projected += 1;
+            }
+            Err(error) => log::error!("Projector failed on {}: {:#}", stream.id, error),
+        }
+        if erased {
+            if let Err(error) = finish_erasure(store, snapshots, read_pool, &stream.id).await {
+                log::error!("Projector failed to erase {}: {:#}", stream.id, error);
+            }
+        }
+    }
+    Ok((projected, notifications))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patient_helper::process_patient_command;
+    use crate::pii_store::PatientKeyStore;
+    use crate::snapshot_store::NoSnapshots;
+    use crate::test_support::{
+        add_patient, memory_read_pool, memory_write_pool, sqlite_event_store, test_meta,
+        update_patient,
+    };
+    use crate::types::commands::patient_stream_id;
+    use uuid::Uuid;
+
+    // Nothing is kept between passes but the checkpoints in read.db, so each pass here
+    // is the projector starting again after a restart
+    #[tokio::test]
+    async fn resumes_from_the_stored_checkpoint() {
+        let write_pool = memory_write_pool().await;
+        let read_pool = memory_read_pool().await;
+        let keys = PatientKeyStore::new(write_pool.clone());
+        let store = PiiEventStore::new(sqlite_event_store(&write_pool).await, keys.clone());
+        let snapshots = SqliteSnapshotStore::new(write_pool, 20, keys);
+        let id = Uuid::new_v4();
+        process_patient_command(store.clone(), NoSnapshots, &add_patient(id), &test_meta())
+            .await
+            .unwrap();
+
+        let (projected, notifications) = catch_up_patient_streams(&store, &snapshots, &read_pool)
+            .await
+            .unwrap();
+        assert_eq!(projected, 1);
+        assert_eq!(notifications.len(), 1);
+
+        process_patient_command(
+            store.clone(),
+            NoSnapshots,
+            &update_patient(id, 1, "John Doe"),
+            &test_meta(),
+        )
+        .await
+        .unwrap();
+        let (projected, notifications) = catch_up_patient_streams(&store, &snapshots, &read_pool)
+            .await
+            .unwrap();
+        assert_eq!(projected, 1);
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].version, 2);
+
+        let (projected, notifications) = catch_up_patient_streams(&store, &snapshots, &read_pool)
+            .await
+            .unwrap();
+        assert_eq!(projected, 0);
+        assert!(notifications.is_empty());
+        let checkpoints = get_projection_checkpoints(read_pool, PATIENT_PROJECTION)
+            .await
+            .unwrap();
+        assert_eq!(checkpoints.get(&patient_stream_id(&id)), Some(&2));
+    }
+}
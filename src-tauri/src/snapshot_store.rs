@@ -0,0 +1,274 @@
+use crate::command_handler::StreamVersion;
+use crate::pii_store::{seal_pii, unseal_pii, PatientKeyStore};
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::Utc;
+use cosmo_store::common::i64_event_version::EventVersion;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite};
+
+#[derive(Clone, Debug)]
+pub struct Snapshot<State, Version> {
+    pub(crate) version: Version,
+    pub(crate) state: State,
+}
+
+#[async_trait]
+pub trait SnapshotStore<State, Version> {
+    async fn load(&self, stream_id: &str) -> Result<Option<Snapshot<State, Version>>>;
+
+    // Called after every append with the versions before and after it
+    async fn save(
+        &self,
+        stream_id: &str,
+        previous_version: i64,
+        version: &Version,
+        state: &State,
+    ) -> Result<()>;
+}
+
+// For handlers that always replay the full stream
+#[cfg(test)]
+#[derive(Clone, Debug, Default)]
+pub struct NoSnapshots;
+
+#[cfg(test)]
+#[async_trait]
+impl<State, Version> SnapshotStore<State, Version> for NoSnapshots
+where
+    State: Sync + 'static,
+    Version: Sync + 'static,
+{
+    async fn load(&self, _stream_id: &str) -> Result<Option<Snapshot<State, Version>>> {
+        Ok(None)
+    }
+
+    async fn save(
+        &self,
+        _stream_id: &str,
+        _previous_version: i64,
+        _version: &Version,
+        _state: &State,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Keeps the serialized aggregate state in the `snapshots` table of write.db,
+// saving a new one every `every` events. PII fields are sealed with the same
+// patient key as the events, so shredding the key covers snapshots as well.
+#[derive(Clone, Debug)]
+pub struct SqliteSnapshotStore {
+    pool: Pool<Sqlite>,
+    every: i64,
+    keys: PatientKeyStore,
+}
+
+impl SqliteSnapshotStore {
+    pub fn new(pool: Pool<Sqlite>, every: i64, keys: PatientKeyStore) -> Self {
+        SqliteSnapshotStore {
+            pool,
+            every: every.max(1),
+            keys,
+        }
+    }
+
+    // Nothing to fold from once a patient is erased
+    pub async fn delete(&self, stream_id: &str) -> Result<u64> {
+        let deleted = sqlx::query("DELETE FROM snapshots WHERE stream_id = $1")
+            .bind(stream_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(deleted.rows_affected())
+    }
+}
+
+#[async_trait]
+impl<State> SnapshotStore<State, EventVersion> for SqliteSnapshotStore
+where
+    State: Serialize + for<'de> Deserialize<'de> + Send + Sync + 'static,
+{
+    async fn load(&self, stream_id: &str) -> Result<Option<Snapshot<State, EventVersion>>> {
+        let row: Option<(i64, String)> = sqlx::query_as(
+            "SELECT version, state FROM snapshots WHERE stream_id = $1 ORDER BY version DESC LIMIT 1",
+        )
+        .bind(stream_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some((version, state)) = row else {
+            return Ok(None);
+        };
+        // Without a key the sealed fields read as erased, like the events do
+        let cipher = self.keys.key(stream_id).await?;
+        let decoded: Result<State> = serde_json::from_str(&state)
+            .map_err(anyhow::Error::from)
+            .and_then(|state| unseal_pii(cipher.as_ref(), &state))
+            .and_then(|state| Ok(serde_json::from_value(state)?));
+        match decoded {
+            Ok(state) => Ok(Some(Snapshot {
+                version: EventVersion(version),
+                state,
+            })),
+            // E.g. written before the state type changed; the events are the source of
+            // truth, so replay them from the start instead of failing every command
+            Err(error) => {
+                log::warn!(
+                    "Ignoring undecodable snapshot of {} at version {}: {}",
+                    stream_id,
+                    version,
+                    error
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    async fn save(
+        &self,
+        stream_id: &str,
+        previous_version: i64,
+        version: &EventVersion,
+        state: &State,
+    ) -> Result<()> {
+        // Only when the append crossed a multiple of `every`
+        if version.number() / self.every <= previous_version / self.every {
+            return Ok(());
+        }
+        // The append created the key; if it is gone the patient was erased meanwhile
+        let Some(cipher) = self.keys.key(stream_id).await? else {
+            log::info!("Not snapshotting erased stream {}", stream_id);
+            return Ok(());
+        };
+        let state = seal_pii(&cipher, &serde_json::to_value(state)?)?;
+        sqlx::query(
+            "INSERT INTO snapshots (stream_id, version, state, created_utc) VALUES ($1, $2, $3, $4) ON CONFLICT(stream_id, version) DO NOTHING",
+        )
+        .bind(stream_id)
+        .bind(version.number())
+        .bind(serde_json::to_string(&state)?)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+        log::info!(
+            "Saved snapshot of {} at version {}",
+            stream_id,
+            version.number()
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{memory_write_pool, test_address};
+    use crate::types::address::Address;
+    use crate::types::patient::Patient;
+    use crate::types::pii::ERASED_PII;
+    use uuid::Uuid;
+
+    fn patient() -> Option<Patient> {
+        Some(Patient {
+            id: Uuid::new_v4(),
+            name: "Jane Doe".to_string(),
+            address: test_address(),
+            age: 40,
+            phone: "555-0100".to_string(),
+            email: "jane@example.com".to_string(),
+            archived: false,
+            erased: false,
+        })
+    }
+
+    async fn snapshot_store(every: i64) -> (SqliteSnapshotStore, PatientKeyStore) {
+        let pool = memory_write_pool().await;
+        let keys = PatientKeyStore::new(pool.clone());
+        (SqliteSnapshotStore::new(pool, every, keys.clone()), keys)
+    }
+
+    #[tokio::test]
+    async fn saves_only_when_crossing_a_multiple_of_every() {
+        let (snapshots, keys) = snapshot_store(2).await;
+        keys.get_or_create_key("patient-1").await.unwrap();
+        let state = patient();
+
+        snapshots
+            .save("patient-1", 0, &EventVersion(1), &state)
+            .await
+            .unwrap();
+        let none: Option<Snapshot<Option<Patient>, EventVersion>> =
+            snapshots.load("patient-1").await.unwrap();
+        assert!(none.is_none());
+
+        snapshots
+            .save("patient-1", 1, &EventVersion(2), &state)
+            .await
+            .unwrap();
+        let saved: Snapshot<Option<Patient>, EventVersion> =
+            snapshots.load("patient-1").await.unwrap().unwrap();
+        assert_eq!(saved.version, EventVersion(2));
+        assert_eq!(saved.state, state);
+    }
+
+    #[tokio::test]
+    async fn undecodable_snapshot_is_ignored() {
+        let pool = memory_write_pool().await;
+        sqlx::query(
+            "INSERT INTO snapshots (stream_id, version, state, created_utc) VALUES ($1, $2, $3, $4)",
+        )
+        .bind("patient-1")
+        .bind(20)
+        .bind(r#"{"not":"a patient"}"#)
+        .bind(Utc::now())
+        .execute(&pool)
+        .await
+        .unwrap();
+        let snapshots = SqliteSnapshotStore::new(pool.clone(), 20, PatientKeyStore::new(pool));
+
+        let loaded: Option<Snapshot<Option<Patient>, EventVersion>> =
+            snapshots.load("patient-1").await.unwrap();
+
+        assert!(loaded.is_none());
+    }
+
+    #[tokio::test]
+    async fn stored_state_is_sealed_and_reads_as_erased_once_shredded() {
+        let (snapshots, keys) = snapshot_store(1).await;
+        keys.get_or_create_key("patient-1").await.unwrap();
+        let state = patient();
+        snapshots
+            .save("patient-1", 0, &EventVersion(1), &state)
+            .await
+            .unwrap();
+
+        let stored: String = sqlx::query_scalar("SELECT state FROM snapshots")
+            .fetch_one(&snapshots.pool)
+            .await
+            .unwrap();
+        assert!(!stored.contains("Jane Doe"));
+        assert!(!stored.contains("jane@example.com"));
+
+        keys.destroy_key("patient-1").await.unwrap();
+        let loaded: Snapshot<Option<Patient>, EventVersion> =
+            snapshots.load("patient-1").await.unwrap().unwrap();
+        let loaded = loaded.state.unwrap();
+        assert_eq!(loaded.name, ERASED_PII);
+        assert_eq!(loaded.email, ERASED_PII);
+        assert_eq!(loaded.address, Address::erased());
+        assert_eq!(loaded.age, 40);
+    }
+
+    #[tokio::test]
+    async fn does_not_snapshot_a_stream_without_a_key() {
+        let (snapshots, _keys) = snapshot_store(1).await;
+
+        snapshots
+            .save("patient-1", 0, &EventVersion(1), &patient())
+            .await
+            .unwrap();
+
+        let loaded: Option<Snapshot<Option<Patient>, EventVersion>> =
+            snapshots.load("patient-1").await.unwrap();
+        assert!(loaded.is_none());
+    }
+}
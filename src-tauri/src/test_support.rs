@@ -0,0 +1,86 @@
+// Fixtures shared by the unit tests
+use crate::db_helpers::{READ_MIGRATOR, WRITE_MIGRATOR};
+use crate::types::address::Address;
+use crate::types::commands::{
+    patient_stream_id, AddPatient, PatientCommand, UpdatePatient, UpdatePatientAddress,
+};
+use crate::types::event_meta::PatientEventMeta;
+use cosmo_store_sqlx_sqlite::event_store_sqlx_sqlite::EventStoreSQLXSqlite;
+use sqlx::sqlite::SqlitePoolOptions;
+use sqlx::{Pool, Sqlite};
+use uuid::Uuid;
+
+// A single connection, since every connection to sqlite::memory: is its own database
+pub async fn memory_read_pool() -> Pool<Sqlite> {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    READ_MIGRATOR.run(&pool).await.unwrap();
+    pool
+}
+
+pub async fn memory_write_pool() -> Pool<Sqlite> {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    WRITE_MIGRATOR.run(&pool).await.unwrap();
+    pool
+}
+
+// The real SQLite event store, on a write pool from memory_write_pool
+pub async fn sqlite_event_store(write_pool: &Pool<Sqlite>) -> EventStoreSQLXSqlite {
+    EventStoreSQLXSqlite::new(write_pool, "tauri_store")
+        .await
+        .unwrap()
+}
+
+pub fn test_meta() -> PatientEventMeta {
+    PatientEventMeta::new(None, Uuid::nil())
+}
+
+pub fn test_address() -> Address {
+    Address {
+        street: "1 Main St".to_string(),
+        city: "Springfield".to_string(),
+        state: "IL".to_string(),
+        zip: "62701".to_string(),
+    }
+}
+
+pub fn add_patient(id: Uuid) -> PatientCommand {
+    PatientCommand::AddPatient(AddPatient {
+        id,
+        stream_id: patient_stream_id(&id),
+        name: "Jane Doe".to_string(),
+        address: test_address(),
+        version: 0,
+        age: 40,
+        phone: "555-0100".to_string(),
+        email: "jane@example.com".to_string(),
+    })
+}
+
+pub fn update_patient(id: Uuid, version: i64, name: &str) -> PatientCommand {
+    PatientCommand::UpdatePatient(UpdatePatient {
+        id,
+        stream_id: patient_stream_id(&id),
+        version,
+        name: name.to_string(),
+        age: 41,
+        phone: "555-0101".to_string(),
+        email: "jane.doe@example.com".to_string(),
+    })
+}
+
+pub fn update_address(id: Uuid, version: i64, address: Address) -> PatientCommand {
+    PatientCommand::UpdatePatientAddress(UpdatePatientAddress {
+        id,
+        stream_id: patient_stream_id(&id),
+        version,
+        address,
+    })
+}
@@ -1,3 +1,4 @@
+use crate::types::pii::ERASED_PII;
 use serde_derive::{Deserialize, Serialize};
 
 #[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
@@ -7,3 +8,14 @@ pub struct Address {
     pub(crate) state: String,
     pub(crate) zip: String,
 }
+
+impl Address {
+    pub fn erased() -> Self {
+        Address {
+            street: ERASED_PII.to_string(),
+            city: ERASED_PII.to_string(),
+            state: ERASED_PII.to_string(),
+            zip: ERASED_PII.to_string(),
+        }
+    }
+}
@@ -1,8 +1,13 @@
+use crate::types::address::Address;
 use crate::types::commands::PatientCommand;
-use crate::types::events::{PatientAdded, PatientAddressUpdated, PatientEvent, PatientUpdated};
+use crate::types::errors::CommandError;
+use crate::types::events::{
+    PatientAdded, PatientAddressUpdated, PatientArchived, PatientErased, PatientEvent,
+    PatientRestored, PatientUpdated,
+};
 use crate::types::patient::Patient;
+use crate::types::pii::ERASED_PII;
 use cosmo_store_util::aggregate::Aggregate;
-use uuid::Uuid;
 
 #[derive(Clone, Debug)]
 pub struct PatientAggregate {}
@@ -21,6 +26,8 @@ impl Aggregate<Option<Patient>, PatientCommand, PatientEvent> for PatientAggrega
                 age: p.age,
                 phone: p.phone.clone(),
                 email: p.email.clone(),
+                archived: false,
+                erased: false,
             }),
             PatientEvent::PatientUpdated(p) => match state {
                 None => return None,
@@ -31,6 +38,8 @@ impl Aggregate<Option<Patient>, PatientCommand, PatientEvent> for PatientAggrega
                     age: p.age,
                     phone: p.phone.clone(),
                     email: p.email.clone(),
+                    archived: state.archived,
+                    erased: state.erased,
                 }),
             },
             PatientEvent::PatientAddressUpdated(a) => match state {
@@ -42,8 +51,28 @@ impl Aggregate<Option<Patient>, PatientCommand, PatientEvent> for PatientAggrega
                     age: state.age,
                     phone: state.phone.clone(),
                     email: state.email.clone(),
+                    archived: state.archived,
+                    erased: state.erased,
                 }),
             },
+            PatientEvent::PatientArchived(_) => state.map(|state| Patient {
+                archived: true,
+                ..state
+            }),
+            PatientEvent::PatientRestored(_) => state.map(|state| Patient {
+                archived: false,
+                ..state
+            }),
+            // Events before this one already read as erased once the key is gone; this
+            // also blanks the state of anything still holding it decrypted
+            PatientEvent::PatientErased(_) => state.map(|state| Patient {
+                name: ERASED_PII.to_string(),
+                address: Address::erased(),
+                phone: ERASED_PII.to_string(),
+                email: ERASED_PII.to_string(),
+                erased: true,
+                ..state
+            }),
         }
     }
 
@@ -54,7 +83,7 @@ impl Aggregate<Option<Patient>, PatientCommand, PatientEvent> for PatientAggrega
     ) -> anyhow::Result<Vec<PatientEvent>> {
         match command {
             PatientCommand::AddPatient(p) => Ok(vec![PatientEvent::PatientAdded(PatientAdded {
-                id: Uuid::new_v4(),
+                id: p.id,
                 name: p.name.clone(),
                 version: i64::default(),
                 address: p.address.clone(),
@@ -63,14 +92,20 @@ impl Aggregate<Option<Patient>, PatientCommand, PatientEvent> for PatientAggrega
                 email: p.email.clone(),
             })]),
             PatientCommand::UpdatePatient(p) => match state {
-                None => return Err(anyhow::anyhow!("Patient not found")),
+                None => return Err(CommandError::not_found("Patient not found").into()),
+                Some(state) if state.erased => {
+                    return Err(CommandError::erased("Patient is erased").into())
+                }
+                Some(state) if state.archived => {
+                    return Err(CommandError::archived("Patient is archived").into())
+                }
                 Some(state) => {
                     if p.name == state.name
                         && p.age == state.age
                         && p.phone == state.phone
                         && p.email == state.email
                     {
-                        return Err(anyhow::anyhow!("Patient not updated"));
+                        return Err(CommandError::not_updated("Patient not updated").into());
                     }
                     Ok(vec![PatientEvent::PatientUpdated(PatientUpdated {
                         name: p.name.clone(),
@@ -81,10 +116,16 @@ impl Aggregate<Option<Patient>, PatientCommand, PatientEvent> for PatientAggrega
                 }
             },
             PatientCommand::UpdatePatientAddress(a) => match state {
-                None => return Err(anyhow::anyhow!("Patient not found")),
+                None => return Err(CommandError::not_found("Patient not found").into()),
+                Some(state) if state.erased => {
+                    return Err(CommandError::erased("Patient is erased").into())
+                }
+                Some(state) if state.archived => {
+                    return Err(CommandError::archived("Patient is archived").into())
+                }
                 Some(state) => {
                     if a.address == state.address {
-                        return Err(anyhow::anyhow!("Patient address not updated"));
+                        return Err(CommandError::not_updated("Patient address not updated").into());
                     }
                     Ok(vec![PatientEvent::PatientAddressUpdated(
                         PatientAddressUpdated {
@@ -93,8 +134,376 @@ impl Aggregate<Option<Patient>, PatientCommand, PatientEvent> for PatientAggrega
                     )])
                 }
             },
+            PatientCommand::ArchivePatient(_) => match state {
+                None => return Err(CommandError::not_found("Patient not found").into()),
+                Some(state) if state.erased => {
+                    return Err(CommandError::erased("Patient is erased").into())
+                }
+                Some(state) if state.archived => {
+                    return Err(CommandError::not_updated("Patient already archived").into())
+                }
+                Some(_) => Ok(vec![PatientEvent::PatientArchived(PatientArchived {})]),
+            },
+            PatientCommand::RestorePatient(_) => match state {
+                None => return Err(CommandError::not_found("Patient not found").into()),
+                Some(state) if state.erased => {
+                    return Err(CommandError::erased("Patient is erased").into())
+                }
+                Some(state) if !state.archived => {
+                    return Err(CommandError::not_updated("Patient is not archived").into())
+                }
+                Some(_) => Ok(vec![PatientEvent::PatientRestored(PatientRestored {})]),
+            },
+            PatientCommand::ErasePatient(_) => match state {
+                None => return Err(CommandError::not_found("Patient not found").into()),
+                Some(state) if state.erased => {
+                    return Err(CommandError::not_updated("Patient already erased").into())
+                }
+                Some(_) => Ok(vec![PatientEvent::PatientErased(PatientErased {})]),
+            },
         }
     }
 }
 
 pub const PATIENT_AGGREGATE: PatientAggregate = PatientAggregate {};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_handler::EventCodec;
+    use crate::test_support::{add_patient, test_address, test_meta, update_patient};
+    use crate::types::commands::{
+        patient_stream_id, ArchivePatient, ErasePatient, RestorePatient, UpdatePatient,
+        UpdatePatientAddress,
+    };
+    use proptest::prelude::*;
+    use serde_json::Value;
+    use uuid::Uuid;
+
+    // Given these prior events, when the command runs, then it emits these events or
+    // fails with this error code
+    struct Given {
+        state: Option<Patient>,
+    }
+
+    struct When {
+        result: anyhow::Result<Vec<PatientEvent>>,
+    }
+
+    fn given(events: Vec<PatientEvent>) -> Given {
+        Given {
+            state: fold(&events),
+        }
+    }
+
+    impl Given {
+        fn when(self, command: PatientCommand) -> When {
+            When {
+                result: PATIENT_AGGREGATE.execute(&self.state, &command),
+            }
+        }
+    }
+
+    impl When {
+        fn then_events(self, expected: Vec<PatientEvent>) {
+            assert_eq!(self.result.unwrap(), expected);
+        }
+
+        fn then_error(self, code: &str) {
+            let error = CommandError::from(self.result.unwrap_err());
+            assert_eq!(error.code(), code, "{}", error);
+        }
+    }
+
+    fn fold(events: &[PatientEvent]) -> Option<Patient> {
+        events
+            .iter()
+            .fold(PATIENT_AGGREGATE.init(), |state, event| {
+                PATIENT_AGGREGATE.apply(state, event)
+            })
+    }
+
+    fn patient_added(id: Uuid) -> PatientEvent {
+        PatientEvent::PatientAdded(PatientAdded {
+            id,
+            name: "Jane Doe".to_string(),
+            version: 0,
+            address: test_address(),
+            age: 40,
+            phone: "555-0100".to_string(),
+            email: "jane@example.com".to_string(),
+        })
+    }
+
+    fn archive(id: Uuid) -> PatientCommand {
+        PatientCommand::ArchivePatient(ArchivePatient {
+            id,
+            stream_id: patient_stream_id(&id),
+            version: 1,
+        })
+    }
+
+    fn erase(id: Uuid) -> PatientCommand {
+        PatientCommand::ErasePatient(ErasePatient {
+            id,
+            stream_id: patient_stream_id(&id),
+            version: 1,
+        })
+    }
+
+    #[test]
+    fn add_patient_emits_patient_added_with_the_command_id() {
+        let id = Uuid::new_v4();
+        given(vec![])
+            .when(add_patient(id))
+            .then_events(vec![patient_added(id)]);
+    }
+
+    #[test]
+    fn update_emits_patient_updated() {
+        let id = Uuid::new_v4();
+        given(vec![patient_added(id)])
+            .when(update_patient(id, 1, "John Doe"))
+            .then_events(vec![PatientEvent::PatientUpdated(PatientUpdated {
+                name: "John Doe".to_string(),
+                age: 41,
+                phone: "555-0101".to_string(),
+                email: "jane.doe@example.com".to_string(),
+            })]);
+    }
+
+    #[test]
+    fn update_without_changes_is_not_updated() {
+        let id = Uuid::new_v4();
+        given(vec![patient_added(id)])
+            .when(PatientCommand::UpdatePatient(UpdatePatient {
+                id,
+                stream_id: patient_stream_id(&id),
+                version: 1,
+                name: "Jane Doe".to_string(),
+                age: 40,
+                phone: "555-0100".to_string(),
+                email: "jane@example.com".to_string(),
+            }))
+            .then_error("not_updated");
+    }
+
+    #[test]
+    fn address_update_of_archived_patient_is_rejected() {
+        let id = Uuid::new_v4();
+        given(vec![
+            patient_added(id),
+            PatientEvent::PatientArchived(PatientArchived {}),
+        ])
+        .when(PatientCommand::UpdatePatientAddress(UpdatePatientAddress {
+            id,
+            stream_id: patient_stream_id(&id),
+            version: 2,
+            address: test_address(),
+        }))
+        .then_error("archived");
+    }
+
+    #[test]
+    fn archiving_twice_is_not_updated() {
+        let id = Uuid::new_v4();
+        given(vec![
+            patient_added(id),
+            PatientEvent::PatientArchived(PatientArchived {}),
+        ])
+        .when(archive(id))
+        .then_error("not_updated");
+    }
+
+    #[test]
+    fn erase_emits_patient_erased() {
+        let id = Uuid::new_v4();
+        given(vec![patient_added(id)])
+            .when(erase(id))
+            .then_events(vec![PatientEvent::PatientErased(PatientErased {})]);
+    }
+
+    #[test]
+    fn erased_patient_cannot_be_updated() {
+        let id = Uuid::new_v4();
+        given(vec![
+            patient_added(id),
+            PatientEvent::PatientErased(PatientErased {}),
+        ])
+        .when(update_patient(id, 2, "John Doe"))
+        .then_error("erased");
+    }
+
+    fn text() -> impl Strategy<Value = String> {
+        "[A-Za-z0-9 .@-]{0,12}"
+    }
+
+    fn uuid() -> impl Strategy<Value = Uuid> {
+        any::<u128>().prop_map(Uuid::from_u128)
+    }
+
+    fn address() -> impl Strategy<Value = Address> {
+        (text(), text(), text(), text()).prop_map(|(street, city, state, zip)| Address {
+            street,
+            city,
+            state,
+            zip,
+        })
+    }
+
+    fn patient() -> impl Strategy<Value = Patient> {
+        (
+            uuid(),
+            text(),
+            address(),
+            any::<i32>(),
+            text(),
+            text(),
+            any::<bool>(),
+            any::<bool>(),
+        )
+            .prop_map(
+                |(id, name, address, age, phone, email, archived, erased)| Patient {
+                    id,
+                    name,
+                    address,
+                    age,
+                    phone,
+                    email,
+                    archived,
+                    erased,
+                },
+            )
+    }
+
+    fn event() -> impl Strategy<Value = PatientEvent> {
+        prop_oneof![
+            (uuid(), text(), address(), any::<i32>(), text(), text()).prop_map(
+                |(id, name, address, age, phone, email)| {
+                    PatientEvent::PatientAdded(PatientAdded {
+                        id,
+                        name,
+                        version: 0,
+                        address,
+                        age,
+                        phone,
+                        email,
+                    })
+                }
+            ),
+            (text(), any::<i32>(), text(), text()).prop_map(|(name, age, phone, email)| {
+                PatientEvent::PatientUpdated(PatientUpdated {
+                    name,
+                    age,
+                    phone,
+                    email,
+                })
+            }),
+            address().prop_map(|address| {
+                PatientEvent::PatientAddressUpdated(PatientAddressUpdated { address })
+            }),
+            Just(PatientEvent::PatientArchived(PatientArchived {})),
+            Just(PatientEvent::PatientRestored(PatientRestored {})),
+            Just(PatientEvent::PatientErased(PatientErased {})),
+        ]
+    }
+
+    fn update_command() -> impl Strategy<Value = PatientCommand> {
+        (uuid(), any::<i64>(), text(), any::<i32>(), text(), text()).prop_map(
+            |(id, version, name, age, phone, email)| {
+                PatientCommand::UpdatePatient(UpdatePatient {
+                    id,
+                    stream_id: patient_stream_id(&id),
+                    version,
+                    name,
+                    age,
+                    phone,
+                    email,
+                })
+            },
+        )
+    }
+
+    // Commands for one patient; versions are irrelevant to the aggregate
+    fn command(id: Uuid) -> impl Strategy<Value = PatientCommand> {
+        let stream_id = patient_stream_id(&id);
+        prop_oneof![
+            Just(add_patient(id)),
+            (text(), any::<i32>(), text(), text()).prop_map({
+                let stream_id = stream_id.clone();
+                move |(name, age, phone, email)| {
+                    PatientCommand::UpdatePatient(UpdatePatient {
+                        id,
+                        stream_id: stream_id.clone(),
+                        version: 0,
+                        name,
+                        age,
+                        phone,
+                        email,
+                    })
+                }
+            }),
+            address().prop_map({
+                let stream_id = stream_id.clone();
+                move |address| {
+                    PatientCommand::UpdatePatientAddress(UpdatePatientAddress {
+                        id,
+                        stream_id: stream_id.clone(),
+                        version: 0,
+                        address,
+                    })
+                }
+            }),
+            Just(archive(id)),
+            Just(PatientCommand::RestorePatient(RestorePatient {
+                id,
+                stream_id: stream_id.clone(),
+                version: 0,
+            })),
+            Just(erase(id)),
+        ]
+    }
+
+    fn round_trip(event: PatientEvent) -> PatientEvent {
+        let written = event.encode(&test_meta()).unwrap();
+        let data: Value = serde_json::from_str(&written.data.to_string()).unwrap();
+        PatientEvent::decode(&written.name, &data, written.metadata.as_ref()).unwrap()
+    }
+
+    proptest! {
+        #[test]
+        fn apply_never_panics(state in proptest::option::of(patient()), event in event()) {
+            let _ = PATIENT_AGGREGATE.apply(state, &event);
+        }
+
+        #[test]
+        fn update_on_no_patient_always_fails(command in update_command()) {
+            let error = CommandError::from(PATIENT_AGGREGATE.execute(&None, &command).unwrap_err());
+            prop_assert_eq!(error.code(), "not_found");
+        }
+
+        // Events are replayed the way the store hands them back: encoded, stored as
+        // JSON text and decoded again by name
+        #[test]
+        fn replaying_stored_events_reproduces_the_state(
+            commands in uuid().prop_flat_map(|id| proptest::collection::vec(command(id), 0..20))
+        ) {
+            let mut state = PATIENT_AGGREGATE.init();
+            let mut emitted = vec![];
+            for command in &commands {
+                if let Ok(events) = PATIENT_AGGREGATE.execute(&state, command) {
+                    for event in events {
+                        state = PATIENT_AGGREGATE.apply(state, &event);
+                        emitted.push(event);
+                    }
+                }
+            }
+            let replayed = emitted
+                .iter()
+                .map(|event| round_trip(event.clone()))
+                .collect::<Vec<_>>();
+            prop_assert_eq!(&replayed, &emitted);
+            prop_assert_eq!(fold(&replayed), state);
+        }
+    }
+}
@@ -1,11 +1,24 @@
-use cosmo_store::common::i64_event_version::EventVersion;
-use cosmo_store::types::event_read_range::EventsReadRange;
 use crate::types::address::Address;
-use uuid::Uuid;
 use crate::types::patient_db::PatientMeta;
+use cosmo_store::common::i64_event_version::EventVersion;
+use cosmo_store::types::expected_version::ExpectedVersion;
+use uuid::Uuid;
 
 pub type StreamId = String;
 
+pub const PATIENT_STREAM_PREFIX: &str = "patient-";
+
+// The only place a patient's stream name is derived from its id
+pub fn patient_stream_id(id: &Uuid) -> StreamId {
+    format!("{}{}", PATIENT_STREAM_PREFIX, id)
+}
+
+pub fn patient_id_from_stream_id(stream_id: &str) -> Option<Uuid> {
+    stream_id
+        .strip_prefix(PATIENT_STREAM_PREFIX)
+        .and_then(|id| Uuid::parse_str(id).ok())
+}
+
 #[derive(Clone, Debug)]
 pub struct AddPatient {
     pub(crate) id: Uuid,
@@ -37,11 +50,35 @@ pub struct UpdatePatientAddress {
     pub(crate) address: Address,
 }
 
+#[derive(Clone, Debug)]
+pub struct ArchivePatient {
+    pub(crate) id: Uuid,
+    pub(crate) stream_id: StreamId,
+    pub(crate) version: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct RestorePatient {
+    pub(crate) id: Uuid,
+    pub(crate) stream_id: StreamId,
+    pub(crate) version: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct ErasePatient {
+    pub(crate) id: Uuid,
+    pub(crate) stream_id: StreamId,
+    pub(crate) version: i64,
+}
+
 #[derive(Clone, Debug)]
 pub enum PatientCommand {
     AddPatient(AddPatient),
     UpdatePatient(UpdatePatient),
     UpdatePatientAddress(UpdatePatientAddress),
+    ArchivePatient(ArchivePatient),
+    RestorePatient(RestorePatient),
+    ErasePatient(ErasePatient),
 }
 
 impl From<PatientCommand> for PatientMeta {
@@ -62,6 +99,21 @@ impl From<PatientCommand> for PatientMeta {
                 stream_id: p.stream_id,
                 version: p.version,
             },
+            PatientCommand::ArchivePatient(p) => PatientMeta {
+                id: p.id,
+                stream_id: p.stream_id,
+                version: p.version,
+            },
+            PatientCommand::RestorePatient(p) => PatientMeta {
+                id: p.id,
+                stream_id: p.stream_id,
+                version: p.version,
+            },
+            PatientCommand::ErasePatient(p) => PatientMeta {
+                id: p.id,
+                stream_id: p.stream_id,
+                version: p.version,
+            },
         }
     }
 }
@@ -72,19 +124,80 @@ impl From<PatientCommand> for StreamId {
             PatientCommand::AddPatient(p) => p.stream_id,
             PatientCommand::UpdatePatient(p) => p.stream_id,
             PatientCommand::UpdatePatientAddress(p) => p.stream_id,
+            PatientCommand::ArchivePatient(p) => p.stream_id,
+            PatientCommand::RestorePatient(p) => p.stream_id,
+            PatientCommand::ErasePatient(p) => p.stream_id,
         }
     }
 }
 
-impl From<PatientCommand> for EventsReadRange<EventVersion> {
+// New patients must not have a stream yet; updates must be based on the latest version.
+// CosmoStore's Exact is the version the next event will get, one past the command's.
+impl From<PatientCommand> for ExpectedVersion<EventVersion> {
     fn from(value: PatientCommand) -> Self {
         match value {
-            PatientCommand::AddPatient(p) => EventsReadRange::FromVersion(EventVersion(p.version)),
-            PatientCommand::UpdatePatient(p) => EventsReadRange::FromVersion(EventVersion(p.version)),
-            PatientCommand::UpdatePatientAddress(p) => {
-                EventsReadRange::FromVersion(EventVersion(p.version))
-            }
+            PatientCommand::AddPatient(_) => ExpectedVersion::NoStream,
+            PatientCommand::UpdatePatient(p) => next_version(p.version),
+            PatientCommand::UpdatePatientAddress(p) => next_version(p.version),
+            PatientCommand::ArchivePatient(p) => next_version(p.version),
+            PatientCommand::RestorePatient(p) => next_version(p.version),
+            PatientCommand::ErasePatient(p) => next_version(p.version),
         }
     }
 }
 
+fn next_version(version: i64) -> ExpectedVersion<EventVersion> {
+    ExpectedVersion::Exact(EventVersion(version + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db_helpers::upsert_patient;
+    use crate::test_support::{add_patient, memory_read_pool};
+    use crate::types::aggregate::PATIENT_AGGREGATE;
+    use crate::types::events::PatientEvent;
+    use cosmo_store_util::aggregate::Aggregate;
+
+    #[test]
+    fn stream_id_round_trips_to_the_patient_id() {
+        let id = Uuid::new_v4();
+        assert_eq!(patient_stream_id(&id), format!("patient-{}", id));
+        assert_eq!(patient_id_from_stream_id(&patient_stream_id(&id)), Some(id));
+        assert_eq!(patient_id_from_stream_id("patient-not-a-uuid"), None);
+        assert_eq!(patient_id_from_stream_id(&id.to_string()), None);
+    }
+
+    #[tokio::test]
+    async fn stream_aggregate_and_read_row_share_one_id() {
+        let id = Uuid::new_v4();
+        let command = add_patient(id);
+        let stream_id = StreamId::from(command.clone());
+        assert_eq!(stream_id, patient_stream_id(&id));
+
+        let events = PATIENT_AGGREGATE.execute(&None, &command).unwrap();
+        match events.as_slice() {
+            [PatientEvent::PatientAdded(added)] => assert_eq!(added.id, id),
+            other => panic!("expected one PatientAdded, got {:?}", other),
+        }
+        let patient = events
+            .iter()
+            .fold(PATIENT_AGGREGATE.init(), |state, event| {
+                PATIENT_AGGREGATE.apply(state, event)
+            })
+            .unwrap();
+        assert_eq!(patient.id, id);
+
+        let read_pool = memory_read_pool().await;
+        upsert_patient(read_pool.clone(), patient, 1, stream_id.clone())
+            .await
+            .unwrap();
+        let (row_id, row_stream_id): (Uuid, String) =
+            sqlx::query_as("SELECT id, stream_id FROM Patient")
+                .fetch_one(&read_pool)
+                .await
+                .unwrap();
+        assert_eq!(row_id, id);
+        assert_eq!(row_stream_id, stream_id);
+    }
+}
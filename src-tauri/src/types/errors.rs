@@ -0,0 +1,136 @@
+use serde_derive::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct ConcurrencyConflict {
+    pub(crate) stream_id: String,
+    pub(crate) expected_version: i64,
+    pub(crate) current_version: i64,
+}
+
+// What the webview receives when a command fails. `code` is stable and is what
+// the frontend should match on; `message` is for humans only.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(tag = "code", rename_all = "snake_case")]
+pub enum CommandError {
+    Validation { message: String },
+    NotFound { message: String },
+    NotUpdated { message: String },
+    Archived { message: String },
+    Erased { message: String },
+    NotErasable { message: String },
+    ConcurrencyConflict(ConcurrencyConflict),
+    Storage { message: String },
+    Internal { message: String },
+}
+
+impl CommandError {
+    pub fn validation(message: impl Into<String>) -> Self {
+        CommandError::Validation {
+            message: message.into(),
+        }
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        CommandError::NotFound {
+            message: message.into(),
+        }
+    }
+
+    pub fn not_updated(message: impl Into<String>) -> Self {
+        CommandError::NotUpdated {
+            message: message.into(),
+        }
+    }
+
+    pub fn archived(message: impl Into<String>) -> Self {
+        CommandError::Archived {
+            message: message.into(),
+        }
+    }
+
+    pub fn erased(message: impl Into<String>) -> Self {
+        CommandError::Erased {
+            message: message.into(),
+        }
+    }
+
+    pub fn not_erasable(message: impl Into<String>) -> Self {
+        CommandError::NotErasable {
+            message: message.into(),
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            CommandError::Validation { .. } => "validation",
+            CommandError::NotFound { .. } => "not_found",
+            CommandError::NotUpdated { .. } => "not_updated",
+            CommandError::Archived { .. } => "archived",
+            CommandError::Erased { .. } => "erased",
+            CommandError::NotErasable { .. } => "not_erasable",
+            CommandError::ConcurrencyConflict(_) => "concurrency_conflict",
+            CommandError::Storage { .. } => "storage",
+            CommandError::Internal { .. } => "internal",
+        }
+    }
+}
+
+impl Display for CommandError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::Validation { message }
+            | CommandError::NotFound { message }
+            | CommandError::NotUpdated { message }
+            | CommandError::Archived { message }
+            | CommandError::Erased { message }
+            | CommandError::NotErasable { message }
+            | CommandError::Storage { message }
+            | CommandError::Internal { message } => write!(f, "{}: {}", self.code(), message),
+            CommandError::ConcurrencyConflict(conflict) => write!(
+                f,
+                "{}: {} expected version {}, current version {}",
+                self.code(),
+                conflict.stream_id,
+                conflict.expected_version,
+                conflict.current_version
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl From<sqlx::Error> for CommandError {
+    fn from(value: sqlx::Error) -> Self {
+        CommandError::Storage {
+            message: value.to_string(),
+        }
+    }
+}
+
+impl From<tauri::Error> for CommandError {
+    fn from(value: tauri::Error) -> Self {
+        CommandError::Internal {
+            message: value.to_string(),
+        }
+    }
+}
+
+// Aggregates and the event store speak anyhow, so typed errors travel inside it
+// and are recovered here
+impl From<anyhow::Error> for CommandError {
+    fn from(value: anyhow::Error) -> Self {
+        if let Some(error) = value.downcast_ref::<CommandError>() {
+            return error.clone();
+        }
+        if let Some(error) = value.downcast_ref::<sqlx::Error>() {
+            return CommandError::Storage {
+                message: error.to_string(),
+            };
+        }
+        CommandError::Internal {
+            message: format!("{:#}", value),
+        }
+    }
+}
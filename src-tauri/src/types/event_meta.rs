@@ -0,0 +1,44 @@
+use crate::types::events::PATIENT_EVENT_SCHEMA_VERSION;
+use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
+use uuid::Uuid;
+
+pub const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// Optional context the webview sends with a command, so one UI action can be traced
+// through every event it produced
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CommandContext {
+    pub(crate) correlation_id: Option<Uuid>,
+    pub(crate) causation_id: Option<Uuid>,
+    pub(crate) user: Option<String>,
+}
+
+// Stored with every patient event for clinical audit
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct PatientEventMeta {
+    pub(crate) correlation_id: Uuid,
+    pub(crate) causation_id: Option<Uuid>,
+    pub(crate) user: Option<String>,
+    pub(crate) device_id: Uuid,
+    pub(crate) app_version: String,
+    pub(crate) timestamp: DateTime<Utc>,
+    // Payload shape version; missing on events written before it was recorded
+    #[serde(default)]
+    pub(crate) schema_version: u32,
+}
+
+impl PatientEventMeta {
+    pub fn new(context: Option<CommandContext>, device_id: Uuid) -> Self {
+        let context = context.unwrap_or_default();
+        PatientEventMeta {
+            correlation_id: context.correlation_id.unwrap_or_else(Uuid::new_v4),
+            causation_id: context.causation_id,
+            user: context.user,
+            device_id,
+            app_version: APP_VERSION.to_string(),
+            timestamp: Utc::now(),
+            schema_version: PATIENT_EVENT_SCHEMA_VERSION,
+        }
+    }
+}
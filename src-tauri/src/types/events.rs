@@ -1,9 +1,25 @@
+use crate::command_handler::EventCodec;
 use crate::types::address::Address;
+use crate::types::event_meta::PatientEventMeta;
+use crate::types::upcasters::upcast_patient_event;
+use anyhow::{bail, Result};
 use cosmo_store::types::event_write::EventWrite;
 use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
 use uuid::Uuid;
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+pub const PATIENT_ADDED: &str = "PatientAdded";
+pub const PATIENT_UPDATED: &str = "PatientUpdated";
+pub const PATIENT_ADDRESS_UPDATED: &str = "PatientAddressUpdated";
+pub const PATIENT_ARCHIVED: &str = "PatientArchived";
+pub const PATIENT_RESTORED: &str = "PatientRestored";
+pub const PATIENT_ERASED: &str = "PatientErased";
+// Name every event was written with before they were named per variant (schema version 0)
+pub const LEGACY_PATIENT_EVENT: &str = "patient_event";
+// Current payload shape; older payloads go through types::upcasters first
+pub const PATIENT_EVENT_SCHEMA_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct PatientAdded {
     pub(crate) id: Uuid,
     pub(crate) name: String,
@@ -14,7 +30,7 @@ pub struct PatientAdded {
     pub(crate) email: String,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct PatientUpdated {
     pub(crate) name: String,
     pub(crate) age: i32,
@@ -22,27 +38,95 @@ pub struct PatientUpdated {
     pub(crate) email: String,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct PatientAddressUpdated {
     pub(crate) address: Address,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct PatientArchived {}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct PatientRestored {}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct PatientErased {}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub enum PatientEvent {
     PatientAdded(PatientAdded),
     PatientUpdated(PatientUpdated),
     PatientAddressUpdated(PatientAddressUpdated),
+    PatientArchived(PatientArchived),
+    PatientRestored(PatientRestored),
+    PatientErased(PatientErased),
 }
 
-impl From<PatientEvent> for EventWrite<PatientEvent, PatientEvent> {
-    fn from(value: PatientEvent) -> Self {
-        EventWrite {
+impl PatientEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PatientEvent::PatientAdded(_) => PATIENT_ADDED,
+            PatientEvent::PatientUpdated(_) => PATIENT_UPDATED,
+            PatientEvent::PatientAddressUpdated(_) => PATIENT_ADDRESS_UPDATED,
+            PatientEvent::PatientArchived(_) => PATIENT_ARCHIVED,
+            PatientEvent::PatientRestored(_) => PATIENT_RESTORED,
+            PatientEvent::PatientErased(_) => PATIENT_ERASED,
+        }
+    }
+
+    pub fn schema_version(&self) -> u32 {
+        match self {
+            PatientEvent::PatientAdded(_) => PATIENT_EVENT_SCHEMA_VERSION,
+            PatientEvent::PatientUpdated(_) => PATIENT_EVENT_SCHEMA_VERSION,
+            PatientEvent::PatientAddressUpdated(_) => PATIENT_EVENT_SCHEMA_VERSION,
+            PatientEvent::PatientArchived(_) => PATIENT_EVENT_SCHEMA_VERSION,
+            PatientEvent::PatientRestored(_) => PATIENT_EVENT_SCHEMA_VERSION,
+            PatientEvent::PatientErased(_) => PATIENT_EVENT_SCHEMA_VERSION,
+        }
+    }
+}
+
+// Each event is stored under its variant name with only its own payload, so the
+// store can be filtered by event type without deserializing everything
+impl EventCodec<Value, PatientEventMeta> for PatientEvent {
+    fn encode(self, metadata: &PatientEventMeta) -> Result<EventWrite<Value, PatientEventMeta>> {
+        let name = self.name();
+        let schema_version = self.schema_version();
+        let data = match self {
+            PatientEvent::PatientAdded(e) => serde_json::to_value(e)?,
+            PatientEvent::PatientUpdated(e) => serde_json::to_value(e)?,
+            PatientEvent::PatientAddressUpdated(e) => serde_json::to_value(e)?,
+            PatientEvent::PatientArchived(e) => serde_json::to_value(e)?,
+            PatientEvent::PatientRestored(e) => serde_json::to_value(e)?,
+            PatientEvent::PatientErased(e) => serde_json::to_value(e)?,
+        };
+        Ok(EventWrite {
             id: Uuid::new_v4(),
-            correlation_id: None,
-            causation_id: None,
-            name: "patient_event".to_string(),
-            data: value,
-            metadata: None,
+            correlation_id: Some(metadata.correlation_id),
+            causation_id: metadata.causation_id,
+            name: name.to_string(),
+            data,
+            metadata: Some(PatientEventMeta {
+                schema_version,
+                ..metadata.clone()
+            }),
+        })
+    }
+
+    fn decode(name: &str, data: &Value, metadata: Option<&PatientEventMeta>) -> Result<Self> {
+        // Events written before the schema version was recorded count as version 0
+        let schema_version = metadata.map_or(0, |m| m.schema_version);
+        let (name, data) = upcast_patient_event(name, schema_version, data.clone())?;
+        match name.as_str() {
+            PATIENT_ADDED => Ok(PatientEvent::PatientAdded(serde_json::from_value(data)?)),
+            PATIENT_UPDATED => Ok(PatientEvent::PatientUpdated(serde_json::from_value(data)?)),
+            PATIENT_ADDRESS_UPDATED => Ok(PatientEvent::PatientAddressUpdated(
+                serde_json::from_value(data)?,
+            )),
+            PATIENT_ARCHIVED => Ok(PatientEvent::PatientArchived(serde_json::from_value(data)?)),
+            PATIENT_RESTORED => Ok(PatientEvent::PatientRestored(serde_json::from_value(data)?)),
+            PATIENT_ERASED => Ok(PatientEvent::PatientErased(serde_json::from_value(data)?)),
+            other => bail!("Unknown patient event {}", other),
         }
     }
 }
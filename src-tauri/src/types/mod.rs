@@ -1,6 +1,14 @@
 pub mod address;
 pub mod aggregate;
 pub mod commands;
+pub mod errors;
+pub mod event_meta;
 pub mod events;
+pub mod notifications;
 pub mod patient;
 pub mod patient_db;
+pub mod patient_history;
+pub mod patient_input;
+pub mod patient_query;
+pub mod pii;
+pub mod upcasters;
@@ -0,0 +1,34 @@
+use crate::types::events::PatientEvent;
+use serde_derive::Serialize;
+use uuid::Uuid;
+
+pub const PATIENT_ADDED_NOTIFICATION: &str = "patient://added";
+pub const PATIENT_UPDATED_NOTIFICATION: &str = "patient://updated";
+pub const PATIENT_ADDRESS_UPDATED_NOTIFICATION: &str = "patient://address-updated";
+pub const PATIENT_ARCHIVED_NOTIFICATION: &str = "patient://archived";
+pub const PATIENT_RESTORED_NOTIFICATION: &str = "patient://restored";
+pub const PATIENT_ERASED_NOTIFICATION: &str = "patient://erased";
+// Sent with the number of patients once the whole read model has been rebuilt
+pub const PATIENT_READ_MODEL_REBUILT_NOTIFICATION: &str = "patient://rebuilt";
+
+// Sent to every window once a projection of the event has committed
+#[derive(Clone, Debug, Serialize)]
+pub struct PatientNotification {
+    #[serde(skip)]
+    pub(crate) name: &'static str,
+    pub(crate) patient_id: Uuid,
+    pub(crate) stream_id: String,
+    pub(crate) version: i64,
+    pub(crate) changed_fields: Vec<String>,
+}
+
+pub fn patient_notification_name(event: &PatientEvent) -> &'static str {
+    match event {
+        PatientEvent::PatientAdded(_) => PATIENT_ADDED_NOTIFICATION,
+        PatientEvent::PatientUpdated(_) => PATIENT_UPDATED_NOTIFICATION,
+        PatientEvent::PatientAddressUpdated(_) => PATIENT_ADDRESS_UPDATED_NOTIFICATION,
+        PatientEvent::PatientArchived(_) => PATIENT_ARCHIVED_NOTIFICATION,
+        PatientEvent::PatientRestored(_) => PATIENT_RESTORED_NOTIFICATION,
+        PatientEvent::PatientErased(_) => PATIENT_ERASED_NOTIFICATION,
+    }
+}
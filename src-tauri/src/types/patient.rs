@@ -1,7 +1,8 @@
 use crate::types::address::Address;
+use serde_derive::{Deserialize, Serialize};
 use uuid::Uuid;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct Patient {
     pub(crate) id: Uuid,
     pub(crate) name: String,
@@ -9,4 +10,9 @@ pub struct Patient {
     pub(crate) age: i32,
     pub(crate) phone: String,
     pub(crate) email: String,
+    // Snapshots taken before archiving existed have no such field
+    #[serde(default)]
+    pub(crate) archived: bool,
+    #[serde(default)]
+    pub(crate) erased: bool,
 }
@@ -18,6 +18,8 @@ pub struct PatientDB {
     pub(crate) age: i32,
     pub(crate) phone: String,
     pub(crate) email: String,
+    pub(crate) archived: bool,
+    pub(crate) erased: bool,
 }
 
 #[derive(Clone, Debug, sqlx::FromRow)]
@@ -26,3 +28,20 @@ pub struct PatientMeta {
     pub(crate) stream_id: String,
     pub(crate) version: i64,
 }
+
+#[derive(Clone, Debug, sqlx::FromRow)]
+pub struct PatientAddressDB {
+    pub(crate) id: Uuid,
+    pub(crate) stream_id: String,
+    pub(crate) version: i64,
+    pub(crate) name: String,
+    pub(crate) age: i32,
+    pub(crate) phone: String,
+    pub(crate) email: String,
+    pub(crate) archived: bool,
+    pub(crate) erased: bool,
+    pub(crate) street: String,
+    pub(crate) city: String,
+    pub(crate) state: String,
+    pub(crate) zip: String,
+}
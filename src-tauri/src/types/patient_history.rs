@@ -0,0 +1,78 @@
+use crate::types::event_meta::PatientEventMeta;
+use crate::types::patient::Patient;
+use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct FieldChange {
+    pub(crate) field: String,
+    pub(crate) before: Option<String>,
+    pub(crate) after: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PatientHistoryEntry {
+    pub(crate) version: i64,
+    pub(crate) event_name: String,
+    pub(crate) timestamp: DateTime<Utc>,
+    pub(crate) metadata: Option<PatientEventMeta>,
+    pub(crate) changes: Vec<FieldChange>,
+}
+
+// Point in a patient's stream to fold up to, inclusive
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "value", rename_all = "snake_case")]
+pub enum AsOf {
+    Version(i64),
+    Timestamp(DateTime<Utc>),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PatientAsOf {
+    pub(crate) version: i64,
+    pub(crate) timestamp: DateTime<Utc>,
+    pub(crate) patient: Patient,
+}
+
+// Flat field list used for field-level diffs, address fields are prefixed
+fn patient_fields(patient: &Patient) -> Vec<(&'static str, String)> {
+    vec![
+        ("name", patient.name.clone()),
+        ("age", patient.age.to_string()),
+        ("phone", patient.phone.clone()),
+        ("email", patient.email.clone()),
+        ("address.street", patient.address.street.clone()),
+        ("address.city", patient.address.city.clone()),
+        ("address.state", patient.address.state.clone()),
+        ("address.zip", patient.address.zip.clone()),
+        ("archived", patient.archived.to_string()),
+        ("erased", patient.erased.to_string()),
+    ]
+}
+
+pub fn diff_patients(before: &Option<Patient>, after: &Option<Patient>) -> Vec<FieldChange> {
+    let before_fields = before.as_ref().map(patient_fields);
+    let after_fields = after.as_ref().map(patient_fields);
+    let field_names = before_fields
+        .as_ref()
+        .or(after_fields.as_ref())
+        .map(|fields| fields.iter().map(|(field, _)| *field).collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    field_names
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, field)| {
+            let old = before_fields.as_ref().map(|fields| fields[i].1.clone());
+            let new = after_fields.as_ref().map(|fields| fields[i].1.clone());
+            if old == new {
+                return None;
+            }
+            Some(FieldChange {
+                field: field.to_string(),
+                before: old,
+                after: new,
+            })
+        })
+        .collect()
+}
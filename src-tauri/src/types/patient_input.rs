@@ -0,0 +1,125 @@
+use crate::types::address::Address;
+use crate::types::commands::patient_stream_id;
+use crate::types::errors::CommandError;
+use serde_derive::{Deserialize, Serialize};
+use uuid::Uuid;
+
+pub const MAX_PATIENT_AGE: i32 = 150;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AddPatientInput {
+    pub(crate) name: String,
+    pub(crate) age: i32,
+    pub(crate) phone: String,
+    pub(crate) email: String,
+    pub(crate) address: Address,
+}
+
+impl AddPatientInput {
+    pub fn validate(&self) -> Result<(), CommandError> {
+        validate_details(&self.name, self.age, &self.phone, &self.email)?;
+        validate_address(&self.address)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UpdatePatientInput {
+    pub(crate) id: Uuid,
+    pub(crate) stream_id: String,
+    pub(crate) version: i64,
+    pub(crate) name: String,
+    pub(crate) age: i32,
+    pub(crate) phone: String,
+    pub(crate) email: String,
+}
+
+impl UpdatePatientInput {
+    pub fn validate(&self) -> Result<(), CommandError> {
+        validate_stream_id(&self.id, &self.stream_id)?;
+        validate_details(&self.name, self.age, &self.phone, &self.email)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UpdatePatientAddressInput {
+    pub(crate) id: Uuid,
+    pub(crate) stream_id: String,
+    pub(crate) version: i64,
+    pub(crate) address: Address,
+}
+
+impl UpdatePatientAddressInput {
+    pub fn validate(&self) -> Result<(), CommandError> {
+        validate_stream_id(&self.id, &self.stream_id)?;
+        validate_address(&self.address)
+    }
+}
+
+// Identifies a patient at a version, for commands that carry no other data
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PatientVersionInput {
+    pub(crate) id: Uuid,
+    pub(crate) stream_id: String,
+    pub(crate) version: i64,
+}
+
+impl PatientVersionInput {
+    pub fn validate(&self) -> Result<(), CommandError> {
+        validate_stream_id(&self.id, &self.stream_id)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PatientCreated {
+    pub(crate) id: Uuid,
+    pub(crate) stream_id: String,
+    pub(crate) version: i64,
+}
+
+pub fn validate_stream_id(id: &Uuid, stream_id: &str) -> Result<(), CommandError> {
+    if stream_id != patient_stream_id(id) {
+        return Err(CommandError::validation(format!(
+            "Stream id {} does not belong to patient {}",
+            stream_id, id
+        )));
+    }
+    Ok(())
+}
+
+pub fn validate_details(
+    name: &str,
+    age: i32,
+    phone: &str,
+    email: &str,
+) -> Result<(), CommandError> {
+    if name.trim().is_empty() {
+        return Err(CommandError::validation("Patient name is required"));
+    }
+    if !(0..=MAX_PATIENT_AGE).contains(&age) {
+        return Err(CommandError::validation(format!(
+            "Patient age must be between 0 and {}",
+            MAX_PATIENT_AGE
+        )));
+    }
+    if phone.trim().is_empty() {
+        return Err(CommandError::validation("Patient phone is required"));
+    }
+    // Email is optional, but when present it has to at least look like one
+    if !email.is_empty() && !email.contains('@') {
+        return Err(CommandError::validation("Patient email is not valid"));
+    }
+    Ok(())
+}
+
+pub fn validate_address(address: &Address) -> Result<(), CommandError> {
+    if address.street.trim().is_empty()
+        || address.city.trim().is_empty()
+        || address.state.trim().is_empty()
+        || address.zip.trim().is_empty()
+    {
+        return Err(CommandError::validation(
+            "Patient address must have street, city, state and zip",
+        ));
+    }
+    Ok(())
+}
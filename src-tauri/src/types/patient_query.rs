@@ -0,0 +1,116 @@
+use crate::types::address::Address;
+use crate::types::patient_db::PatientAddressDB;
+use serde_derive::{Deserialize, Serialize};
+use uuid::Uuid;
+
+pub const DEFAULT_PAGE_SIZE: i64 = 20;
+pub const MAX_PAGE_SIZE: i64 = 100;
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PatientSortBy {
+    #[default]
+    Name,
+    Age,
+    Email,
+    Version,
+}
+
+impl PatientSortBy {
+    // Only ever interpolated into ORDER BY, so it must stay a fixed column name
+    pub fn column(&self) -> &'static str {
+        match self {
+            PatientSortBy::Name => "p.name",
+            PatientSortBy::Age => "p.age",
+            PatientSortBy::Email => "p.email",
+            PatientSortBy::Version => "p.version",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortDirection {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl SortDirection {
+    pub fn keyword(&self) -> &'static str {
+        match self {
+            SortDirection::Asc => "ASC",
+            SortDirection::Desc => "DESC",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PatientListQuery {
+    pub(crate) page: Option<i64>,
+    pub(crate) page_size: Option<i64>,
+    pub(crate) sort_by: Option<PatientSortBy>,
+    pub(crate) sort_direction: Option<SortDirection>,
+    // Archived patients are hidden unless asked for
+    pub(crate) include_archived: Option<bool>,
+}
+
+impl PatientListQuery {
+    pub fn page(&self) -> i64 {
+        self.page.unwrap_or(1).max(1)
+    }
+
+    pub fn page_size(&self) -> i64 {
+        self.page_size
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE)
+    }
+
+    pub fn offset(&self) -> i64 {
+        (self.page() - 1) * self.page_size()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PatientListItem {
+    pub(crate) id: Uuid,
+    pub(crate) stream_id: String,
+    pub(crate) version: i64,
+    pub(crate) name: String,
+    pub(crate) age: i32,
+    pub(crate) phone: String,
+    pub(crate) email: String,
+    pub(crate) archived: bool,
+    pub(crate) erased: bool,
+    pub(crate) address: Address,
+}
+
+impl From<PatientAddressDB> for PatientListItem {
+    fn from(value: PatientAddressDB) -> Self {
+        PatientListItem {
+            id: value.id,
+            stream_id: value.stream_id,
+            version: value.version,
+            name: value.name,
+            age: value.age,
+            phone: value.phone,
+            email: value.email,
+            archived: value.archived,
+            erased: value.erased,
+            address: Address {
+                street: value.street,
+                city: value.city,
+                state: value.state,
+                zip: value.zip,
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PatientList {
+    pub(crate) items: Vec<PatientListItem>,
+    pub(crate) total: i64,
+    pub(crate) page: i64,
+    pub(crate) page_size: i64,
+}
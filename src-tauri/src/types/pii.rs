@@ -0,0 +1,16 @@
+use crate::types::address::Address;
+use serde_json::Value;
+
+// Top-level payload fields holding patient PII. They are encrypted with the patient's
+// key before they reach the event store.
+pub const PII_FIELDS: [&str; 4] = ["name", "phone", "email", "address"];
+
+// What a PII field reads as once the patient's key has been destroyed
+pub const ERASED_PII: &str = "[erased]";
+
+pub fn erased_pii_value(field: &str) -> Value {
+    match field {
+        "address" => serde_json::to_value(Address::erased()).unwrap_or(Value::Null),
+        _ => Value::String(ERASED_PII.to_string()),
+    }
+}
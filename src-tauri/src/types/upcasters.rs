@@ -0,0 +1,139 @@
+use crate::types::events::{LEGACY_PATIENT_EVENT, PATIENT_EVENT_SCHEMA_VERSION};
+use anyhow::{bail, Result};
+use serde_json::Value;
+
+// Takes a payload written at `from_version` of `event_name` to `from_version + 1`.
+// It may also rename the event.
+struct Upcaster {
+    event_name: &'static str,
+    from_version: u32,
+    upcast: fn(Value) -> Result<(String, Value)>,
+}
+
+// Add one entry whenever a stored payload shape changes and bump
+// PATIENT_EVENT_SCHEMA_VERSION. Events without an entry for a version are unchanged by it.
+const PATIENT_UPCASTERS: &[Upcaster] = &[Upcaster {
+    event_name: LEGACY_PATIENT_EVENT,
+    from_version: 0,
+    upcast: unwrap_legacy_envelope,
+}];
+
+// Version 0: every event was named "patient_event" and stored as the whole externally
+// tagged PatientEvent enum, e.g. {"PatientAdded": {...}}
+fn unwrap_legacy_envelope(data: Value) -> Result<(String, Value)> {
+    match data {
+        Value::Object(map) if map.len() == 1 => {
+            let (name, payload) = map.into_iter().next().unwrap();
+            Ok((name, payload))
+        }
+        other => bail!("Unexpected legacy patient event payload {}", other),
+    }
+}
+
+// Migrates a stored payload to the current schema version before it is deserialized
+pub fn upcast_patient_event(
+    name: &str,
+    schema_version: u32,
+    data: Value,
+) -> Result<(String, Value)> {
+    if schema_version > PATIENT_EVENT_SCHEMA_VERSION {
+        bail!(
+            "Event {} has schema version {}, newer than supported {}",
+            name,
+            schema_version,
+            PATIENT_EVENT_SCHEMA_VERSION
+        );
+    }
+    let mut name = name.to_string();
+    let mut data = data;
+    for version in schema_version..PATIENT_EVENT_SCHEMA_VERSION {
+        if let Some(upcaster) = PATIENT_UPCASTERS
+            .iter()
+            .find(|u| u.event_name == name && u.from_version == version)
+        {
+            (name, data) = (upcaster.upcast)(data)?;
+        }
+    }
+    Ok((name, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_handler::EventCodec;
+    use crate::types::event_meta::PatientEventMeta;
+    use crate::types::events::PatientEvent;
+    use serde_derive::Deserialize;
+
+    // An event as it sits in the store at some schema version, and what it must
+    // decode to today
+    #[derive(Deserialize)]
+    struct StoredEventFixture {
+        name: String,
+        data: Value,
+        metadata: Option<PatientEventMeta>,
+        expected: PatientEvent,
+    }
+
+    fn load(json: &str) -> Vec<StoredEventFixture> {
+        let fixtures: Vec<StoredEventFixture> = serde_json::from_str(json).unwrap();
+        assert!(!fixtures.is_empty());
+        fixtures
+    }
+
+    fn assert_decodes(fixtures: &[StoredEventFixture]) {
+        for fixture in fixtures {
+            let decoded =
+                PatientEvent::decode(&fixture.name, &fixture.data, fixture.metadata.as_ref())
+                    .unwrap();
+            assert_eq!(decoded, fixture.expected, "decoding {}", fixture.name);
+        }
+    }
+
+    #[test]
+    fn decodes_v0_envelopes_without_metadata() {
+        let fixtures = load(include_str!(
+            "../../tests/fixtures/patient_events/v0_without_metadata.json"
+        ));
+        assert!(fixtures.iter().all(|f| f.metadata.is_none()));
+        assert_decodes(&fixtures);
+    }
+
+    #[test]
+    fn decodes_v0_envelopes_with_metadata_but_no_schema_version() {
+        let fixtures = load(include_str!(
+            "../../tests/fixtures/patient_events/v0_metadata_without_schema_version.json"
+        ));
+        assert!(fixtures
+            .iter()
+            .all(|f| f.metadata.as_ref().map(|m| m.schema_version) == Some(0)));
+        assert_decodes(&fixtures);
+    }
+
+    #[test]
+    fn decodes_v1_payloads_of_every_event() {
+        let fixtures = load(include_str!("../../tests/fixtures/patient_events/v1.json"));
+        let mut names: Vec<&str> = fixtures.iter().map(|f| f.expected.name()).collect();
+        names.dedup();
+        assert_eq!(names.len(), 6);
+        assert_decodes(&fixtures);
+    }
+
+    #[test]
+    fn rejects_schema_versions_newer_than_supported() {
+        let fixture = load(include_str!("../../tests/fixtures/patient_events/v1.json")).remove(0);
+        let metadata = PatientEventMeta {
+            schema_version: PATIENT_EVENT_SCHEMA_VERSION + 1,
+            ..fixture.metadata.unwrap()
+        };
+
+        let error =
+            PatientEvent::decode(&fixture.name, &fixture.data, Some(&metadata)).unwrap_err();
+
+        assert!(
+            error.to_string().contains("newer than supported"),
+            "{}",
+            error
+        );
+    }
+}
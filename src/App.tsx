@@ -1,8 +1,78 @@
-import { useState } from "react";
+import { useEffect, useState } from "react";
 import reactLogo from "./assets/react.svg";
 import { invoke } from "@tauri-apps/api/core";
+import { listen } from "@tauri-apps/api/event";
 import "./App.css";
 
+type Address = {
+  street: string;
+  city: string;
+  state: string;
+  zip: string;
+};
+
+type PatientListItem = {
+  id: string;
+  stream_id: string;
+  version: number;
+  name: string;
+  age: number;
+  phone: string;
+  email: string;
+  archived: boolean;
+  erased: boolean;
+  address: Address;
+};
+
+type AddPatientInput = {
+  name: string;
+  age: number;
+  phone: string;
+  email: string;
+  address: Address;
+};
+
+type PatientCreated = {
+  id: string;
+  stream_id: string;
+  version: number;
+};
+
+type CommandError =
+  | {
+      code: "validation" | "not_found" | "not_updated" | "archived" | "erased" | "not_erasable" | "storage" | "internal";
+      message: string;
+    }
+  | {
+      code: "concurrency_conflict";
+      stream_id: string;
+      expected_version: number;
+      current_version: number;
+    };
+
+type PatientNotification = {
+  patient_id: string;
+  stream_id: string;
+  version: number;
+  changed_fields: string[];
+};
+
+const PATIENT_NOTIFICATIONS = [
+  "patient://added",
+  "patient://updated",
+  "patient://address-updated",
+  "patient://archived",
+  "patient://restored",
+  "patient://erased",
+];
+
+type PatientList = {
+  items: PatientListItem[];
+  total: number;
+  page: number;
+  page_size: number;
+};
+
 function App() {
   const [greetMsg, setGreetMsg] = useState("");
   const [name, setName] = useState("");
@@ -13,15 +83,54 @@ function App() {
   }
 
   async function add_patient() {
-    const res = await invoke<String>("add_patient");
-    console.log(res);
+    const input: AddPatientInput = {
+      name,
+      age: 42,
+      phone: "555-123-4567",
+      email: "",
+      address: {
+        street: "123 Main St",
+        city: "Anytown",
+        state: "NY",
+        zip: "12345",
+      },
+    };
+    try {
+      const res = await invoke<PatientCreated>("add_patient", { input });
+      console.log(res);
+    } catch (e) {
+      const error = e as CommandError;
+      console.error(error.code, error);
+    }
   }
 
   async function get_patients() {
-    const res = await invoke<String>("get_patients"); 
+    const res = await invoke<PatientList>("get_patients", {
+      query: { page: 1, page_size: 20, sort_by: "name", sort_direction: "asc" },
+    });
     console.log(res);
   }
 
+  // Refresh whenever any window changes a patient
+  useEffect(() => {
+    const unlisten = PATIENT_NOTIFICATIONS.map((event) =>
+      listen<PatientNotification>(event, (e) => {
+        console.log(event, e.payload);
+        get_patients();
+      }),
+    );
+    // Sent with the number of rebuilt patients after a full read-model rebuild
+    unlisten.push(
+      listen<number>("patient://rebuilt", (e) => {
+        console.log("patient://rebuilt", e.payload);
+        get_patients();
+      }),
+    );
+    return () => {
+      unlisten.forEach((p) => p.then((f) => f()));
+    };
+  }, []);
+
   return (
     <div className="container">
       <h1>Welcome to Tauri!</h1>